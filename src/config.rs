@@ -0,0 +1,165 @@
+//! Defaults for command line options from a TOML config file.
+//!
+//! Top level keys are the long names of command line options, like `relay` or
+//! `ticket-type`, and apply to every subcommand that has that option. Tables
+//! under `[profile.<name>]` contain the same keys and override the top level
+//! ones when the profile is selected with `--profile <name>`. Options given on
+//! the command line always win over the config file.
+//!
+//! ```toml
+//! format = "cid"
+//!
+//! [profile.office]
+//! relay = ["https://relay1.example.com", "https://relay2.example.com"]
+//! dns-origin = "dns.example.com"
+//! pkarr-relay = "https://dns.example.com/pkarr"
+//! magic-ipv4-addr = "0.0.0.0:4433"
+//! ```
+//!
+//! This is also where the directories sendme keeps its config and data in are
+//! defined.
+
+use std::{ffi::OsString, path::PathBuf};
+
+use anyhow::Context;
+use clap::{ArgAction, Command};
+use toml::{Table, Value};
+
+/// Environment variable to override the config directory.
+const CONFIG_DIR_ENV: &str = "SENDME_CONFIG_DIR";
+
+/// Environment variable to override the data directory.
+const DATA_DIR_ENV: &str = "SENDME_DATA_DIR";
+
+/// The config directory, if there is one on this platform.
+pub fn config_dir() -> Option<PathBuf> {
+    match std::env::var_os(CONFIG_DIR_ENV) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => Some(dirs::config_dir()?.join("sendme")),
+    }
+}
+
+/// The directory for data like the transfer history, if there is one on this platform.
+pub fn data_dir() -> Option<PathBuf> {
+    match std::env::var_os(DATA_DIR_ENV) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => Some(dirs::data_local_dir()?.join("sendme")),
+    }
+}
+
+/// The path of the default config file.
+pub fn config_file() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+/// Find the value of an option like `--name value` or `--name=value` in the raw arguments.
+///
+/// The config has to be read before the arguments can be parsed, since it
+/// changes the defaults of the parser.
+fn find_option(args: &[OsString], name: &str) -> Option<OsString> {
+    let flag = format!("--{name}");
+    let prefix = format!("--{name}=");
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_str()?;
+        if arg == "--" {
+            break;
+        } else if arg == flag {
+            return args.next().cloned();
+        } else if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.into());
+        }
+    }
+    None
+}
+
+/// Load the options from the config file, with the profile selected in `args` applied.
+///
+/// A missing default config file is not an error, there just are no options.
+pub fn load(args: &[OsString]) -> anyhow::Result<Table> {
+    let (path, explicit) = match find_option(args, "config") {
+        Some(path) => (PathBuf::from(path), true),
+        None => match config_file() {
+            Some(path) => (path, false),
+            None => return Ok(Table::new()),
+        },
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let mut options: Table = text
+        .parse()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    let profiles = match options.remove("profile") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => anyhow::bail!("profile in {} must be a table", path.display()),
+        None => Table::new(),
+    };
+    if let Some(name) = find_option(args, "profile") {
+        let name = name.to_string_lossy();
+        let profile = match profiles.get(name.as_ref()) {
+            Some(Value::Table(profile)) => profile,
+            Some(_) => anyhow::bail!("profile {name} in {} must be a table", path.display()),
+            None => anyhow::bail!("no profile {name} in {}", path.display()),
+        };
+        options.extend(profile.clone());
+    }
+    Ok(options)
+}
+
+/// Convert a config value to the string clap would get on the command line.
+fn value_to_strings(key: &str, value: &Value) -> anyhow::Result<Vec<String>> {
+    match value {
+        Value::String(s) => Ok(vec![s.clone()]),
+        Value::Integer(i) => Ok(vec![i.to_string()]),
+        Value::Float(f) => Ok(vec![f.to_string()]),
+        Value::Boolean(b) => Ok(vec![b.to_string()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::Array(_) | Value::Table(_) => {
+                    anyhow::bail!("{key} must be a list of plain values")
+                }
+                value => Ok(value_to_strings(key, value)?.remove(0)),
+            })
+            .collect(),
+        Value::Datetime(_) | Value::Table(_) => anyhow::bail!("{key} has an unsupported type"),
+    }
+}
+
+/// Set the options as defaults of the matching arguments of all subcommands.
+pub fn apply(mut command: Command, options: &Table) -> anyhow::Result<Command> {
+    for (key, value) in options {
+        let long = key.replace('_', "-");
+        let values = value_to_strings(key, value)?;
+        let mut found = false;
+        let subcommands = command
+            .get_subcommands()
+            .map(|sub| sub.get_name().to_string())
+            .collect::<Vec<_>>();
+        for name in subcommands {
+            let ids = command
+                .find_subcommand(&name)
+                .into_iter()
+                .flat_map(|sub| sub.get_arguments())
+                .filter(|arg| !arg.is_positional() && arg.get_long() == Some(long.as_str()))
+                .map(|arg| (arg.get_id().clone(), arg.get_action().clone()))
+                .collect::<Vec<_>>();
+            for (id, action) in ids {
+                found = true;
+                anyhow::ensure!(
+                    values.len() == 1 || matches!(action, ArgAction::Append),
+                    "{key} does not take a list"
+                );
+                let values = values.clone();
+                command = command.mut_subcommand(&name, |sub| {
+                    sub.mut_arg(id, |arg| arg.default_values(values))
+                });
+            }
+        }
+        anyhow::ensure!(found, "unknown option {key}");
+    }
+    Ok(command)
+}
@@ -0,0 +1,708 @@
+//! A long running sendme process that shares data until told otherwise.
+//!
+//! The daemon keeps one endpoint and a persistent blob store running. Other
+//! sendme invocations talk to it over a unix socket in the data directory,
+//! using one line of JSON per request and response. This way
+//! `sendme send --daemon` can return right away, and tickets stay valid after
+//! it exits.
+//!
+//! Every share is kept alive by a tag in the blob store, and the details like
+//! the download counter are kept in a JSON file next to it, so shares survive
+//! a restart of the daemon. Only the blobs of current shares are served, and
+//! the garbage collection of the store removes the data of stopped shares.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use iroh::{
+    endpoint::Connecting,
+    protocol::{ProtocolHandler, Router},
+    Endpoint,
+};
+use iroh_blobs::{
+    hashseq::HashSeq,
+    net_protocol::Blobs,
+    provider::{self, CustomEventSender},
+    store::{fs::Store, GcConfig, Map, MapEntry, ReadableStore, Store as _},
+    ticket::BlobTicket,
+    util::Tag,
+    BlobFormat, Hash, HashAndFormat,
+};
+use iroh_io::AsyncSliceReaderExt;
+use n0_future::future::Boxed;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{hashcache, AddrInfoOptions, CommonArgs};
+
+/// Prefix of the names of the tags that keep the shared data in the store.
+const TAG_PREFIX: &str = "sendme-share-";
+
+/// How often the data of stopped shares is removed from the store.
+const GC_PERIOD: Duration = Duration::from_secs(60);
+
+/// A request from a client to the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// Share a file or directory.
+    Send {
+        path: PathBuf,
+        ticket_type: AddrInfoOptions,
+        /// When to stop sharing, if ever.
+        expires: Option<DateTime<Utc>>,
+    },
+    /// List all shares.
+    List,
+    /// Stop sharing the collection with the given hash.
+    Stop { hash: Hash },
+}
+
+/// The response of the daemon to a [`Request`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Shared {
+        share: Box<Share>,
+        ticket: BlobTicket,
+    },
+    Shares {
+        shares: Vec<(Share, BlobTicket)>,
+    },
+    Stopped,
+    Error {
+        message: String,
+    },
+}
+
+/// Data shared by the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Share {
+    /// The hash of the collection.
+    pub hash: Hash,
+    /// The name of the data, usually the top level file or directory name.
+    pub name: String,
+    /// The total size of the files.
+    pub size: u64,
+    /// The path that is shared.
+    pub path: PathBuf,
+    /// How often all of the data was downloaded, approximately, see [`DownloadCounter`].
+    pub downloads: u64,
+    /// When the daemon stops sharing the data, if ever.
+    pub expires: Option<DateTime<Utc>>,
+    /// The files as they were when they were shared, to notice changes.
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, hashcache::FileId>,
+}
+
+impl Share {
+    fn is_expired(&self) -> bool {
+        self.expires
+            .map(|expires| expires <= Utc::now())
+            .unwrap_or(false)
+    }
+}
+
+/// The path of the socket the daemon listens on.
+pub fn socket_path() -> anyhow::Result<PathBuf> {
+    Ok(crate::config::data_dir()
+        .context("no data directory")?
+        .join("daemon.sock"))
+}
+
+/// A connection to a running daemon.
+#[cfg(unix)]
+pub struct Client {
+    stream: BufReader<tokio::net::UnixStream>,
+}
+
+#[cfg(unix)]
+impl Client {
+    /// Connect to the daemon, returning `None` if it is not running.
+    pub async fn connect() -> anyhow::Result<Option<Self>> {
+        let path = socket_path()?;
+        match tokio::net::UnixStream::connect(&path).await {
+            Ok(stream) => Ok(Some(Self {
+                stream: BufReader::new(stream),
+            })),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e).with_context(|| format!("failed to connect to {}", path.display())),
+        }
+    }
+
+    /// Send a request and wait for the response.
+    pub async fn request(&mut self, request: &Request) -> anyhow::Result<Response> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.stream.get_mut().write_all(&line).await?;
+        let mut line = String::new();
+        self.stream.read_line(&mut line).await?;
+        anyhow::ensure!(!line.is_empty(), "daemon closed the connection");
+        let response = serde_json::from_str(&line)?;
+        if let Response::Error { message } = response {
+            anyhow::bail!("{message}");
+        }
+        Ok(response)
+    }
+}
+
+/// A connection to a running daemon.
+///
+/// The daemon only runs on unix, so there never is one.
+#[cfg(not(unix))]
+pub struct Client;
+
+#[cfg(not(unix))]
+impl Client {
+    /// Connect to the daemon, returning `None` if it is not running.
+    pub async fn connect() -> anyhow::Result<Option<Self>> {
+        Ok(None)
+    }
+
+    /// Send a request and wait for the response.
+    pub async fn request(&mut self, _request: &Request) -> anyhow::Result<Response> {
+        anyhow::bail!("the daemon is only supported on unix")
+    }
+}
+
+/// Connect to the daemon, failing if it is not running.
+pub async fn connect() -> anyhow::Result<Client> {
+    Client::connect()
+        .await?
+        .context("the daemon is not running, start it with sendme daemon")
+}
+
+/// The shares of the daemon, by hash, and the file they are persisted in.
+#[derive(Debug)]
+struct Shares {
+    shares: BTreeMap<Hash, Share>,
+    /// the hashes of all blobs of each share
+    blobs: BTreeMap<Hash, BTreeSet<Hash>>,
+    path: PathBuf,
+}
+
+impl Shares {
+    /// Whether the blob with this hash is part of a share.
+    fn serves(&self, hash: &Hash) -> bool {
+        self.blobs.values().any(|blobs| blobs.contains(hash))
+    }
+
+    fn insert(&mut self, share: Share, blobs: BTreeSet<Hash>) {
+        self.blobs.insert(share.hash, blobs);
+        self.shares.insert(share.hash, share);
+    }
+
+    fn remove(&mut self, hash: &Hash) -> Option<Share> {
+        self.blobs.remove(hash);
+        self.shares.remove(hash)
+    }
+
+    /// Write the shares to disk, logging instead of failing on errors.
+    fn save(&self) {
+        let res = serde_json::to_vec_pretty(&self.shares.values().collect::<Vec<_>>())
+            .map_err(anyhow::Error::from)
+            .and_then(|json| {
+                // write and rename, so a crash never leaves a half written file
+                let tmp = self.path.with_extension("json.tmp");
+                std::fs::write(&tmp, json)?;
+                std::fs::rename(&tmp, &self.path)?;
+                Ok(())
+            });
+        if let Err(cause) = res {
+            tracing::warn!("failed to save shares: {cause}");
+        }
+    }
+}
+
+/// Counts the downloads of each share from the provider events.
+///
+/// The events only tell which hash was requested, not which ranges, so a
+/// download is a connection that got at least the size of the share in one
+/// request for it. The count is approximate: a resumed download or one that
+/// only gets some of the files is not counted, and a receiver that gets all of
+/// the data again on a new connection is counted twice.
+#[derive(Debug, Clone)]
+struct DownloadCounter {
+    shares: Arc<Mutex<Shares>>,
+    /// the requested collection, by connection and request id
+    requests: Arc<Mutex<BTreeMap<(u64, u64), Hash>>>,
+    /// the most recent connections that were counted as a download, and of what
+    counted: Arc<Mutex<VecDeque<(u64, Hash)>>>,
+}
+
+impl DownloadCounter {
+    /// How many counted connections to remember.
+    const MAX_COUNTED: usize = 1024;
+}
+
+impl CustomEventSender for DownloadCounter {
+    fn send(&self, event: provider::Event) -> Boxed<()> {
+        self.try_send(event);
+        Box::pin(std::future::ready(()))
+    }
+
+    fn try_send(&self, event: provider::Event) {
+        match event {
+            provider::Event::GetRequestReceived {
+                connection_id,
+                request_id,
+                hash,
+            } => {
+                self.requests
+                    .lock()
+                    .unwrap()
+                    .insert((connection_id, request_id), hash);
+            }
+            provider::Event::TransferCompleted {
+                connection_id,
+                request_id,
+                stats,
+            } => {
+                let Some(hash) = self
+                    .requests
+                    .lock()
+                    .unwrap()
+                    .remove(&(connection_id, request_id))
+                else {
+                    return;
+                };
+                let mut shares = self.shares.lock().unwrap();
+                let Some(share) = shares.shares.get_mut(&hash) else {
+                    return;
+                };
+                // receivers also make small requests for the sizes and names,
+                // only a request that sent all of the data is a download
+                if stats.send.write_bytes.size < share.size {
+                    return;
+                }
+                // for tiny files the small requests send all of the data as well,
+                // so count every connection only once
+                let mut counted = self.counted.lock().unwrap();
+                if counted.contains(&(connection_id, hash)) {
+                    return;
+                }
+                if counted.len() >= Self::MAX_COUNTED {
+                    counted.pop_front();
+                }
+                counted.push_back((connection_id, hash));
+                share.downloads += 1;
+                shares.save();
+            }
+            provider::Event::TransferAborted {
+                connection_id,
+                request_id,
+                ..
+            } => {
+                self.requests
+                    .lock()
+                    .unwrap()
+                    .remove(&(connection_id, request_id));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The state of the daemon.
+struct Daemon {
+    router: Router,
+    blobs: Blobs<Store>,
+    shares: Arc<Mutex<Shares>>,
+}
+
+impl Daemon {
+    async fn handle(&self, request: Request) -> anyhow::Result<Response> {
+        match request {
+            Request::Send {
+                path,
+                ticket_type,
+                expires,
+            } => {
+                let path = path.canonicalize()?;
+                let files = hashcache::snapshot(&path)?;
+                let existing = self
+                    .shares
+                    .lock()
+                    .unwrap()
+                    .shares
+                    .values()
+                    .find(|share| share.path == path)
+                    .cloned();
+                // sharing the same unchanged path again just gives a new ticket,
+                // without hashing again
+                let share = match existing {
+                    Some(share) if share.files == files && expires.is_some() => {
+                        self.set_expiry(share.hash, expires)?
+                    }
+                    Some(share) if share.files == files => share,
+                    Some(old) => {
+                        let share = self.import(path, files, expires.or(old.expires)).await?;
+                        // the store refers to the files, so the old share would
+                        // no longer match its hash
+                        if share.hash != old.hash {
+                            self.remove(old.hash).await?;
+                        }
+                        share
+                    }
+                    None => self.import(path, files, expires).await?,
+                };
+                let ticket = self.ticket(share.hash, ticket_type).await?;
+                Ok(Response::Shared {
+                    share: Box::new(share),
+                    ticket,
+                })
+            }
+            Request::List => {
+                let shares = self
+                    .shares
+                    .lock()
+                    .unwrap()
+                    .shares
+                    .values()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let mut res = Vec::with_capacity(shares.len());
+                for share in shares {
+                    let ticket = self
+                        .ticket(share.hash, AddrInfoOptions::RelayAndAddresses)
+                        .await?;
+                    res.push((share, ticket));
+                }
+                Ok(Response::Shares { shares: res })
+            }
+            Request::Stop { hash } => {
+                self.remove(hash).await?;
+                Ok(Response::Stopped)
+            }
+        }
+    }
+
+    /// Import the data at `path`, whose files were as in `files` before.
+    async fn import(
+        &self,
+        path: PathBuf,
+        files: BTreeMap<PathBuf, hashcache::FileId>,
+        expires: Option<DateTime<Utc>>,
+    ) -> anyhow::Result<Share> {
+        let (temp_tag, size, collection) =
+            crate::import(path.clone(), self.blobs.store().clone(), false, None).await?;
+        let hash = *temp_tag.hash();
+        self.blobs
+            .store()
+            .set_tag(tag_name(&hash), Some(HashAndFormat::hash_seq(hash)))
+            .await?;
+        let mut share = Share {
+            hash,
+            name: crate::collection_name(&collection).unwrap_or_else(|| hash.to_string()),
+            size,
+            path,
+            downloads: 0,
+            expires,
+            files,
+        };
+        // reading from the store is not Send, so it has to run in the pool of the store
+        let store = self.blobs.store().clone();
+        let blobs = self
+            .blobs
+            .rt()
+            .try_spawn(move || async move { share_blobs(&store, hash).await })?
+            .await??;
+        tracing::info!("sharing {} as {hash}", share.path.display());
+        let mut shares = self.shares.lock().unwrap();
+        // the same data shared again keeps counting its downloads
+        if let Some(old) = shares.shares.get(&hash) {
+            share.downloads = old.downloads;
+        }
+        shares.insert(share.clone(), blobs);
+        shares.save();
+        Ok(share)
+    }
+
+    fn set_expiry(&self, hash: Hash, expires: Option<DateTime<Utc>>) -> anyhow::Result<Share> {
+        let mut shares = self.shares.lock().unwrap();
+        let share = shares
+            .shares
+            .get_mut(&hash)
+            .context("no share with this hash")?;
+        share.expires = expires;
+        let share = share.clone();
+        shares.save();
+        Ok(share)
+    }
+
+    async fn remove(&self, hash: Hash) -> anyhow::Result<()> {
+        {
+            let mut shares = self.shares.lock().unwrap();
+            shares.remove(&hash).context("no share with this hash")?;
+            shares.save();
+        }
+        self.blobs.store().set_tag(tag_name(&hash), None).await?;
+        tracing::info!("stopped sharing {hash}");
+        Ok(())
+    }
+
+    /// Remove all shares that are past their expiry time.
+    async fn remove_expired(&self) -> anyhow::Result<()> {
+        let expired = self
+            .shares
+            .lock()
+            .unwrap()
+            .shares
+            .values()
+            .filter(|share| share.is_expired())
+            .map(|share| share.hash)
+            .collect::<Vec<_>>();
+        for hash in expired {
+            self.remove(hash).await?;
+        }
+        Ok(())
+    }
+
+    async fn ticket(&self, hash: Hash, ticket_type: AddrInfoOptions) -> anyhow::Result<BlobTicket> {
+        let mut addr = self.router.endpoint().node_addr().await?;
+        crate::apply_options(&mut addr, ticket_type);
+        BlobTicket::new(addr, hash, BlobFormat::HashSeq)
+    }
+
+    /// Serve the requests of a single client, until it disconnects.
+    #[cfg(unix)]
+    async fn serve(self: Arc<Self>, stream: tokio::net::UnixStream) -> anyhow::Result<()> {
+        let mut lines = BufReader::new(stream);
+        let mut line = String::new();
+        while lines.read_line(&mut line).await? > 0 {
+            let response = match serde_json::from_str(&line) {
+                Ok(request) => self.handle(request).await,
+                Err(cause) => Err(anyhow::anyhow!("invalid request: {cause}")),
+            }
+            .unwrap_or_else(|cause| Response::Error {
+                message: format!("{cause:#}"),
+            });
+            let mut response = serde_json::to_vec(&response)?;
+            response.push(b'\n');
+            lines.get_mut().write_all(&response).await?;
+            line.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Serves the blobs protocol, but only for the blobs of current shares.
+#[derive(Debug, Clone)]
+struct ShareProtocol {
+    blobs: Blobs<Store>,
+    shares: Arc<Mutex<Shares>>,
+}
+
+impl ProtocolHandler for ShareProtocol {
+    fn accept(&self, conn: Connecting) -> Boxed<anyhow::Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let store = SharedStore {
+                inner: this.blobs.store().clone(),
+                shares: this.shares.clone(),
+            };
+            provider::handle_connection(
+                conn.await?,
+                store,
+                this.blobs.events().clone(),
+                this.blobs.rt().clone(),
+            )
+            .await;
+            Ok(())
+        })
+    }
+
+    fn shutdown(&self) -> Boxed<()> {
+        self.blobs.shutdown()
+    }
+}
+
+/// The store of the daemon, as seen by receivers.
+///
+/// Blobs that are not part of a share are missing, even while they are still
+/// in the store, so stopped shares can not be downloaded any more.
+#[derive(Debug, Clone)]
+struct SharedStore {
+    inner: Store,
+    shares: Arc<Mutex<Shares>>,
+}
+
+impl Map for SharedStore {
+    type Entry = <Store as Map>::Entry;
+
+    async fn get(&self, hash: &Hash) -> io::Result<Option<Self::Entry>> {
+        if !self.shares.lock().unwrap().serves(hash) {
+            return Ok(None);
+        }
+        self.inner.get(hash).await
+    }
+}
+
+/// The hashes of the collection with this hash and of all of its blobs.
+async fn share_blobs(store: &Store, hash: Hash) -> anyhow::Result<BTreeSet<Hash>> {
+    let entry = store
+        .get(&hash)
+        .await?
+        .with_context(|| format!("{hash} is not in the store"))?;
+    let data = MapEntry::data_reader(&entry).await?.read_to_end().await?;
+    let links = HashSeq::new(data).context("invalid hash sequence")?;
+    Ok(std::iter::once(hash).chain(links.iter()).collect())
+}
+
+/// The name of the tag that keeps a share in the store.
+fn tag_name(hash: &Hash) -> Tag {
+    Tag::from(format!("{TAG_PREFIX}{}", hash.to_hex()))
+}
+
+/// Load the shares of a previous run, for which there still are tags in the store.
+async fn load_shares(store: &Store, path: PathBuf) -> anyhow::Result<Shares> {
+    let mut saved = match std::fs::read(&path) {
+        Ok(json) => serde_json::from_slice::<Vec<Share>>(&json)
+            .with_context(|| format!("failed to parse {}", path.display()))?
+            .into_iter()
+            .map(|share| (share.hash, share))
+            .collect::<BTreeMap<_, _>>(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let mut shares = Shares {
+        shares: BTreeMap::new(),
+        blobs: BTreeMap::new(),
+        path,
+    };
+    for tag in store.tags().await? {
+        let (tag, _) = tag?;
+        let Some(hash) = std::str::from_utf8(&tag.0)
+            .ok()
+            .and_then(|name| name.strip_prefix(TAG_PREFIX))
+            .and_then(|hash| hash.parse::<Hash>().ok())
+        else {
+            continue;
+        };
+        let Some(share) = saved.remove(&hash) else {
+            tracing::warn!("no details for shared {hash}, not sharing it");
+            continue;
+        };
+        match share_blobs(store, hash).await {
+            Ok(blobs) => shares.insert(share, blobs),
+            Err(cause) => tracing::warn!("failed to read shared {hash}, not sharing it: {cause}"),
+        }
+    }
+    Ok(shares)
+}
+
+/// Run the daemon until ctrl-c is pressed.
+#[cfg(unix)]
+pub async fn run(common: CommonArgs) -> anyhow::Result<()> {
+    let socket = socket_path()?;
+    if let Some(parent) = socket.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    if Client::connect().await?.is_some() {
+        anyhow::bail!("the daemon is already running");
+    }
+    // a socket file without a daemon is left over from a crash
+    if socket.exists() {
+        tokio::fs::remove_file(&socket).await?;
+    }
+
+    let secret_key = crate::get_or_create_secret(common.verbose > 0)?;
+    let relay = common.relay_mode()?;
+    let mut builder = Endpoint::builder()
+        .alpns(vec![iroh_blobs::protocol::ALPN.to_vec()])
+        .secret_key(secret_key)
+        .relay_mode(relay.clone().into())
+        // publish our address, so tickets with just the node id work
+        .add_discovery(common.pkarr_publisher());
+    if let Some(addr) = common.magic_ipv4_addr {
+        builder = builder.bind_addr_v4(addr);
+    }
+    if let Some(addr) = common.magic_ipv6_addr {
+        builder = builder.bind_addr_v6(addr);
+    }
+    if common.local_discovery {
+        let device = common.device(false);
+        builder = builder
+            .add_discovery(move |secret_key| crate::mdns::discovery(secret_key.public(), device));
+    }
+    let endpoint = builder.bind().await?;
+    let store = Store::load(socket.with_file_name("daemon-store")).await?;
+    let shares = load_shares(&store, socket.with_file_name("daemon-shares.json")).await?;
+    let shares = Arc::new(Mutex::new(shares));
+    let counter = DownloadCounter {
+        shares: shares.clone(),
+        requests: Default::default(),
+        counted: Default::default(),
+    };
+    let blobs = Blobs::builder(store)
+        .events(counter.into())
+        .build(&endpoint);
+    blobs.start_gc(GcConfig {
+        period: GC_PERIOD,
+        done_callback: None,
+    })?;
+    let protocol = ShareProtocol {
+        blobs: blobs.clone(),
+        shares: shares.clone(),
+    };
+    let router = Router::builder(endpoint)
+        .accept(iroh_blobs::ALPN, protocol)
+        .spawn()
+        .await?;
+    // wait for the endpoint to figure out its address before making tickets
+    crate::wait_for_address(router.endpoint(), &relay).await?;
+
+    let listener = tokio::net::UnixListener::bind(&socket)?;
+    println!("daemon running as node {}", router.endpoint().node_id());
+    println!("listening on {}", socket.display());
+    let daemon = Arc::new(Daemon {
+        router,
+        blobs,
+        shares,
+    });
+    let mut expiry = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                let (stream, _) = res?;
+                let daemon = daemon.clone();
+                tokio::spawn(async move {
+                    if let Err(cause) = daemon.serve(stream).await {
+                        tracing::warn!("daemon client failed: {cause}");
+                    }
+                });
+            }
+            _ = expiry.tick() => {
+                if let Err(cause) = daemon.remove_expired().await {
+                    tracing::warn!("failed to remove expired shares: {cause}");
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    println!("shutting down");
+    tokio::fs::remove_file(&socket).await?;
+    daemon.router.shutdown().await?;
+    Ok(())
+}
+
+/// Run the daemon until ctrl-c is pressed.
+#[cfg(not(unix))]
+pub async fn run(_common: CommonArgs) -> anyhow::Result<()> {
+    anyhow::bail!("the daemon is only supported on unix")
+}
@@ -0,0 +1,217 @@
+//! Diagnosing the connection to the sender of a ticket.
+//!
+//! `sendme doctor <ticket>` connects to the sender like `receive` does, but
+//! instead of downloading everything it reports how the connection works:
+//! our own addresses and what they say about the NAT we are behind, whether
+//! the connection is direct or goes over a relay, the round trip time, the
+//! addresses of the sender, and the throughput of a short download.
+//!
+//! A connection usually starts over the relay and is upgraded to a direct one
+//! once hole punching succeeded, so the path is reported again after the
+//! download. `receive -v` prints these changes as they happen.
+
+use std::{collections::BTreeSet, net::IpAddr, time::Duration};
+
+use indicatif::{HumanBytes, HumanDuration};
+use iroh::{
+    endpoint::{Connection, ConnectionType, DirectAddr, DirectAddrType},
+    Endpoint, NodeId,
+};
+use iroh_blobs::{
+    get::{fsm, Stats},
+    protocol::{GetRequest, RangeSpecSeq},
+    store::bao_tree::{ChunkNum, ChunkRanges},
+};
+use n0_future::{task::AbortOnDropHandle, StreamExt};
+
+use crate::{error::SendmeError, CommonArgs, DoctorArgs, RelayModeOption};
+
+/// How long to wait for our own addresses to be known.
+const ADDRESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How much data to download to measure the throughput.
+const PROBE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// How long the throughput probe may take at most.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connect to the sender of a ticket and report on the connection.
+pub async fn run(args: DoctorArgs) -> anyhow::Result<()> {
+    let common = &args.common;
+    let ticket = args.ticket;
+    let endpoint = crate::receive_endpoint(&ticket, common, None).await?;
+    local_report(&endpoint, common).await?;
+
+    let node_id = ticket.node_addr().node_id;
+    println!("connecting to {node_id}");
+    let start = std::time::Instant::now();
+    let connection = endpoint
+        .connect(ticket.node_addr().clone(), iroh_blobs::protocol::ALPN)
+        .await
+        .map_err(|cause| SendmeError::connect(node_id, cause))?;
+    println!("connected in {}ms", start.elapsed().as_millis());
+    path_report(&endpoint, &connection, node_id);
+
+    let (collection, sizes) = crate::get_collection_and_sizes(&connection, &ticket.hash()).await?;
+    // probe with the biggest file, the first size is the one of the metadata blob
+    let biggest = collection
+        .iter()
+        .zip(sizes.iter().skip(1))
+        .max_by_key(|(_, size)| **size)
+        .filter(|(_, size)| **size > 0);
+    match biggest {
+        Some(((name, hash), _)) => {
+            println!("downloading up to {} of {name}", HumanBytes(PROBE_SIZE));
+            match tokio::time::timeout(PROBE_TIMEOUT, probe(&connection, *hash)).await {
+                Ok(Ok(stats)) => println!(
+                    "downloaded {} in {}ms ({}/s)",
+                    HumanBytes(stats.bytes_read),
+                    stats.elapsed.as_millis(),
+                    HumanBytes((stats.bytes_read as f64 / stats.elapsed.as_secs_f64()) as u64),
+                ),
+                Ok(Err(cause)) => println!("download failed: {cause}"),
+                Err(_) => println!(
+                    "download did not finish in {}",
+                    HumanDuration(PROBE_TIMEOUT)
+                ),
+            }
+            // hole punching had some time now, so the path might have changed
+            path_report(&endpoint, &connection, node_id);
+        }
+        None => println!("nothing to download to measure the throughput"),
+    }
+
+    connection.close(0u32.into(), b"done");
+    endpoint.close().await;
+    Ok(())
+}
+
+/// Print our own addresses and what they tell about the network we are in.
+async fn local_report(endpoint: &Endpoint, common: &CommonArgs) -> anyhow::Result<()> {
+    let relay = common.relay_mode()?;
+    println!("this node is {}", endpoint.node_id());
+    // without relays, there is nobody to tell us our public address
+    if !matches!(relay, RelayModeOption::Disabled) {
+        match tokio::time::timeout(ADDRESS_TIMEOUT, endpoint.home_relay().initialized()).await {
+            Ok(Ok(url)) => println!("home relay: {url}"),
+            _ => println!("home relay: none, the relays can not be reached"),
+        }
+    }
+    let addrs = tokio::time::timeout(ADDRESS_TIMEOUT, endpoint.direct_addresses().initialized())
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .unwrap_or_default();
+    println!("direct addresses:");
+    for addr in &addrs {
+        println!("    {} ({})", addr.addr, addr.typ);
+    }
+    println!("nat: {}", nat_hint(&addrs, &relay));
+    Ok(())
+}
+
+/// Guess how hard it is to reach us directly, from the kinds of our addresses.
+fn nat_hint(addrs: &BTreeSet<DirectAddr>, relay: &RelayModeOption) -> &'static str {
+    let public = |typ: DirectAddrType| {
+        addrs
+            .iter()
+            .filter(|addr| addr.typ == typ)
+            .map(|addr| addr.addr)
+            .collect::<Vec<_>>()
+    };
+    let local = addrs
+        .iter()
+        .filter(|addr| addr.typ == DirectAddrType::Local)
+        .map(|addr| addr.addr.ip())
+        .collect::<BTreeSet<IpAddr>>();
+    let stun = public(DirectAddrType::Stun);
+    if !public(DirectAddrType::Portmapped).is_empty() {
+        "the router forwards a port to us, direct connections should work"
+    } else if !public(DirectAddrType::Stun4LocalPort).is_empty() {
+        "behind a NAT with a forwarded port, direct connections should work"
+    } else if stun.iter().any(|addr| local.contains(&addr.ip())) {
+        "none, this node has a public address"
+    } else if stun.len() > 1 {
+        // the NAT used different ports to talk to different relays
+        "behind a NAT that changes ports, direct connections might fail"
+    } else if !stun.is_empty() {
+        "behind a NAT, direct connections usually work"
+    } else if matches!(relay, RelayModeOption::Disabled) {
+        "unknown, there are no relays to ask for our public address"
+    } else {
+        "unknown, UDP to the internet might be blocked"
+    }
+}
+
+/// Print the path of the connection, its round trip time and the addresses of the peer.
+fn path_report(endpoint: &Endpoint, connection: &Connection, node_id: NodeId) {
+    let Some(info) = endpoint.remote_info(node_id) else {
+        return;
+    };
+    println!("path: {}", describe(&info.conn_type));
+    println!("rtt: {:?}", connection.rtt());
+    if let Some(relay) = &info.relay_url {
+        match relay.latency {
+            Some(latency) => println!("    {} (relay, {latency:?})", relay.relay_url),
+            None => println!("    {} (relay)", relay.relay_url),
+        }
+    }
+    for addr in &info.addrs {
+        match addr.latency {
+            Some(latency) => println!("    {} ({latency:?})", addr.addr),
+            None => println!("    {} (not reachable yet)", addr.addr),
+        }
+    }
+}
+
+/// Download the start of a blob, for the transfer statistics.
+async fn probe(connection: &Connection, hash: iroh_blobs::Hash) -> anyhow::Result<Stats> {
+    let chunks = ChunkNum(PROBE_SIZE / 1024);
+    let request = GetRequest::new(
+        hash,
+        RangeSpecSeq::from_ranges([ChunkRanges::from(..chunks)]),
+    );
+    let connected = fsm::start(connection.clone(), request).next().await?;
+    let fsm::ConnectedNext::StartRoot(start) = connected.next().await? else {
+        anyhow::bail!("expected the blob");
+    };
+    let end = start.next().drain().await?;
+    let fsm::EndBlobNext::Closing(closing) = end.next() else {
+        anyhow::bail!("expected the end of the response");
+    };
+    Ok(closing.next().await?)
+}
+
+/// Describe the path of a connection for people.
+fn describe(conn_type: &ConnectionType) -> String {
+    match conn_type {
+        ConnectionType::Direct(addr) => format!("direct ({addr})"),
+        ConnectionType::Relay(url) => format!("relay ({url})"),
+        ConnectionType::Mixed(addr, url) => {
+            format!("relay ({url}), trying direct ({addr})")
+        }
+        ConnectionType::None => "none".to_string(),
+    }
+}
+
+/// Print the path of the connection to a node whenever it changes, until the handle is dropped.
+pub fn log_path_changes(endpoint: &Endpoint, node_id: NodeId) -> Option<AbortOnDropHandle<()>> {
+    let watcher = endpoint.conn_type(node_id).ok()?;
+    let task = tokio::spawn(async move {
+        let mut changes = watcher.stream();
+        let mut last = None;
+        while let Some(conn_type) = changes.next().await {
+            match (&last, &conn_type) {
+                (
+                    Some(ConnectionType::Relay(_) | ConnectionType::Mixed(..)),
+                    ConnectionType::Direct(addr),
+                ) => {
+                    eprintln!("connection upgraded from relay to direct ({addr})")
+                }
+                (_, conn_type) => eprintln!("connection path: {}", describe(conn_type)),
+            }
+            last = Some(conn_type);
+        }
+    });
+    Some(AbortOnDropHandle::new(task))
+}
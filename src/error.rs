@@ -0,0 +1,189 @@
+//! The ways a transfer can fail, by the step that failed.
+use std::{
+    fmt::{Display, Formatter},
+    io,
+    path::{Path, PathBuf},
+};
+
+use iroh::{
+    endpoint::{ConnectionError, ReadError, WriteError},
+    NodeId,
+};
+use iroh_blobs::get::{
+    error::GetError,
+    fsm::{AtBlobHeaderNextError, ConnectedNextError, DecodeError},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::exit;
+
+/// A failure while sending or receiving.
+#[derive(Debug)]
+pub enum SendmeError {
+    /// Hashing a file or directory to send failed.
+    Import {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+    /// The other side could not be connected to.
+    Connect {
+        node_id: NodeId,
+        source: anyhow::Error,
+    },
+    /// A request for data failed, or was not answered as expected.
+    Request(GetError),
+    /// The data that was received could not be verified.
+    Decode(DecodeError),
+    /// Writing the received files failed.
+    Export {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+}
+
+/// The step of a transfer that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Import,
+    Connect,
+    Request,
+    Decode,
+    Export,
+}
+
+impl SendmeError {
+    pub fn import(path: &Path, source: impl Into<anyhow::Error>) -> Self {
+        Self::Import {
+            path: path.to_path_buf(),
+            source: source.into(),
+        }
+    }
+
+    pub fn connect(node_id: NodeId, source: impl Into<anyhow::Error>) -> Self {
+        Self::Connect {
+            node_id,
+            source: source.into(),
+        }
+    }
+
+    pub fn export(path: &Path, source: impl Into<anyhow::Error>) -> Self {
+        Self::Export {
+            path: path.to_path_buf(),
+            source: source.into(),
+        }
+    }
+
+    /// Classify a failed request by its cause.
+    ///
+    /// Anything that is not a network or decode error means that the other
+    /// side did not follow the protocol.
+    pub fn request(cause: anyhow::Error) -> Self {
+        downcast(cause, Self::Decode)
+            .or_else(|cause| downcast(cause, |e: GetError| e.into()))
+            .or_else(|cause| downcast(cause, |e: AtBlobHeaderNextError| GetError::from(e).into()))
+            .or_else(|cause| downcast(cause, |e: ConnectedNextError| GetError::from(e).into()))
+            .or_else(|cause| downcast(cause, |e: ConnectionError| GetError::from(e).into()))
+            .or_else(|cause| downcast(cause, |e: ReadError| GetError::from(e).into()))
+            .or_else(|cause| downcast(cause, |e: WriteError| GetError::from(e).into()))
+            .unwrap_or_else(|cause| Self::Request(GetError::NoncompliantNode(cause)))
+    }
+
+    pub fn step(&self) -> Step {
+        match self {
+            Self::Import { .. } => Step::Import,
+            Self::Connect { .. } => Step::Connect,
+            Self::Request(_) => Step::Request,
+            Self::Decode(_) => Step::Decode,
+            Self::Export { .. } => Step::Export,
+        }
+    }
+
+    /// The exit code for this failure, if it has one of its own.
+    pub fn exit_code(&self) -> Option<i32> {
+        match self {
+            Self::Request(GetError::NotFound(_))
+            | Self::Decode(
+                DecodeError::NotFound
+                | DecodeError::ParentNotFound(_)
+                | DecodeError::LeafNotFound(_),
+            ) => Some(exit::NOT_FOUND),
+            Self::Decode(DecodeError::ParentHashMismatch(_) | DecodeError::LeafHashMismatch(_)) => {
+                Some(exit::INTEGRITY)
+            }
+            Self::Export { source, .. } => source
+                .downcast_ref::<io::Error>()
+                .filter(|e| e.kind() == io::ErrorKind::AlreadyExists)
+                .map(|_| exit::CONFLICT),
+            _ => None,
+        }
+    }
+}
+
+/// Convert the cause into a [`SendmeError`] if it is an `E`, or give it back.
+fn downcast<E>(
+    cause: anyhow::Error,
+    f: impl FnOnce(E) -> SendmeError,
+) -> Result<SendmeError, anyhow::Error>
+where
+    E: Display + std::fmt::Debug + Send + Sync + 'static,
+{
+    cause.downcast::<E>().map(f)
+}
+
+impl From<GetError> for SendmeError {
+    /// Tell failures to decode the data apart from other failed requests.
+    fn from(e: GetError) -> Self {
+        let (wrap, cause): (fn(anyhow::Error) -> GetError, _) = match e {
+            GetError::NotFound(cause) => (GetError::NotFound, cause),
+            GetError::RemoteReset(cause) => (GetError::RemoteReset, cause),
+            GetError::NoncompliantNode(cause) => (GetError::NoncompliantNode, cause),
+            GetError::Io(cause) => (GetError::Io, cause),
+            GetError::BadRequest(cause) => (GetError::BadRequest, cause),
+            GetError::LocalFailure(cause) => (GetError::LocalFailure, cause),
+        };
+        downcast(cause, Self::Decode)
+            .or_else(|cause| downcast(cause, |e: AtBlobHeaderNextError| Self::Decode(e.into())))
+            .unwrap_or_else(|cause| Self::Request(wrap(cause)))
+    }
+}
+
+impl From<DecodeError> for SendmeError {
+    fn from(e: DecodeError) -> Self {
+        Self::Decode(e)
+    }
+}
+
+impl Display for SendmeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Import { path, .. } => write!(f, "failed to import {}", path.display()),
+            Self::Connect { node_id, .. } => write!(f, "failed to connect to {node_id}"),
+            Self::Request(_) => write!(f, "request failed"),
+            Self::Decode(e) => match e {
+                DecodeError::NotFound => write!(f, "the sender no longer has a file"),
+                DecodeError::LeafNotFound(_) | DecodeError::ParentNotFound(_) => {
+                    write!(f, "the sender no longer has part of a file")
+                }
+                DecodeError::LeafHashMismatch(_) | DecodeError::ParentHashMismatch(_) => {
+                    write!(f, "the sender sent wrong data")
+                }
+                DecodeError::Read(_) => write!(f, "failed to read data from the connection"),
+                DecodeError::Io(_) => write!(f, "failed to read or store data"),
+            },
+            Self::Export { path, .. } => write!(f, "failed to export {}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for SendmeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Import { source, .. }
+            | Self::Connect { source, .. }
+            | Self::Export { source, .. } => Some(source.as_ref()),
+            Self::Request(e) => Some(e),
+            Self::Decode(e) => Some(e),
+        }
+    }
+}
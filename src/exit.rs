@@ -0,0 +1,101 @@
+//! Exit codes, so that scripts running sendme can tell failures apart.
+use std::fmt::{Display, Formatter};
+
+use crate::{error::SendmeError, SenderFailure};
+
+/// Any failure that has no code of its own.
+pub const FAILURE: i32 = 1;
+
+/// The command line or the config file is invalid.
+pub const USAGE: i32 = 2;
+
+/// The sender closed the connection, so retrying later will not help.
+pub const SENDER_GONE: i32 = 3;
+
+/// The sender could not be reached, but might be later.
+pub const SENDER_UNREACHABLE: i32 = 4;
+
+/// The sender no longer has the data.
+pub const NOT_FOUND: i32 = 5;
+
+/// The received data did not match its hash.
+pub const INTEGRITY: i32 = 6;
+
+/// A file to export already exists.
+pub const CONFLICT: i32 = 7;
+
+/// The user cancelled.
+pub const ABORTED: i32 = 8;
+
+/// The ticket could not be used.
+pub const BAD_TICKET: i32 = 9;
+
+/// There is not enough free space for the download.
+pub const NO_SPACE: i32 = 10;
+
+/// The exit codes, as shown in the help.
+pub const HELP: &str = "\
+Exit codes:
+  0  success
+  1  any other failure
+  2  invalid command line or options
+  3  the sender is gone, retrying later will not help
+  4  the sender could not be reached, retrying later might
+  5  the sender no longer has the data
+  6  the received data did not match its hash
+  7  a file to receive already exists
+  8  cancelled by the user
+  9  the ticket is invalid or for something else
+  10 not enough free space for the download";
+
+/// An error that ends the process with a specific exit code.
+#[derive(Debug)]
+pub struct ExitError {
+    code: i32,
+    cause: anyhow::Error,
+}
+
+/// Attaches an exit code to an error.
+pub fn with_code(code: i32, cause: impl Into<anyhow::Error>) -> anyhow::Error {
+    ExitError {
+        code,
+        cause: cause.into(),
+    }
+    .into()
+}
+
+impl Display for ExitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.cause, f)
+    }
+}
+
+impl std::error::Error for ExitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause.source()
+    }
+}
+
+/// The exit code for an error.
+pub fn code(error: &anyhow::Error) -> i32 {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<ExitError>() {
+            return e.code;
+        }
+    }
+    let code = error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<SendmeError>())
+        .find_map(SendmeError::exit_code);
+    if let Some(code) = code {
+        return code;
+    }
+    match error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<SenderFailure>())
+    {
+        Some(SenderFailure::Gone(_)) => SENDER_GONE,
+        Some(SenderFailure::Unreachable(_)) => SENDER_UNREACHABLE,
+        None => FAILURE,
+    }
+}
@@ -0,0 +1,310 @@
+//! Finding and removing the stores that sendme processes left behind.
+//!
+//! `sendme send`, `sendme push` and `sendme receive` keep their data in a
+//! `.sendme-send-*`, `.sendme-push-*` or `.sendme-get-*` directory in the
+//! current directory, and remove it when they are done. `sendme send` may
+//! also use the runtime directory or the one given with `--store-dir`. A
+//! process that is killed leaves its directory behind. To tell those apart
+//! from the ones that are still in use, every process holds a lock on a file
+//! in its directory, with its pid in it. The lock goes away with the process,
+//! however it ends, and is taken again to remove a directory, so that a
+//! process that starts using it in the meantime keeps it.
+
+use std::{
+    collections::BTreeSet,
+    fs::{File, OpenOptions},
+    io::{IsTerminal, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use fs4::fs_std::FileExt;
+use indicatif::{HumanBytes, HumanDuration};
+use walkdir::WalkDir;
+
+use crate::GcArgs;
+
+/// The name of the lock file in a store directory.
+const LOCK_FILE: &str = "sendme.lock";
+
+/// The prefixes of the names of store directories.
+const PREFIXES: [&str; 3] = [".sendme-send-", ".sendme-push-", ".sendme-get-"];
+
+/// How long a store directory without a lock file is assumed to be just created.
+const LOCK_GRACE: Duration = Duration::from_secs(60);
+
+/// The lock on a store directory, held while the store is in use.
+///
+/// Drop it before removing the directory, some platforms can not remove
+/// files that are open.
+#[derive(Debug)]
+pub struct StoreLock {
+    _file: File,
+}
+
+/// Create a store directory if needed, and lock it for this process.
+pub fn lock(dir: &Path) -> anyhow::Result<StoreLock> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    if !file.try_lock_exclusive()? {
+        anyhow::bail!("{} is in use by another sendme process", dir.display());
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(StoreLock { _file: file })
+}
+
+/// A store directory that no running process uses.
+#[derive(Debug)]
+pub struct Stale {
+    pub path: PathBuf,
+    /// The process that left it behind, if known.
+    pub pid: Option<u32>,
+    /// The size of all files in it.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl Stale {
+    /// A partial download can be resumed by receiving the same ticket again.
+    pub fn is_download(&self) -> bool {
+        self.path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(".sendme-get-"))
+    }
+}
+
+/// The directories that stores are made in: the current directory, the
+/// runtime directory and `store_dir`, if given.
+fn store_parents(store_dir: Option<&Path>) -> Vec<PathBuf> {
+    let mut dirs = std::env::current_dir().ok().into_iter().collect::<Vec<_>>();
+    dirs.push(crate::runtime_dir());
+    dirs.extend(store_dir.map(Path::to_path_buf));
+    // the runtime directory may well be the current directory
+    let mut seen = BTreeSet::new();
+    dirs.retain(|dir| seen.insert(dir.canonicalize().unwrap_or_else(|_| dir.clone())));
+    dirs
+}
+
+/// Find the store directories in all of `dirs` that no running sendme process uses.
+///
+/// Directories that do not exist are skipped.
+fn find_stale_in(dirs: &[PathBuf]) -> anyhow::Result<Vec<Stale>> {
+    let mut stale = Vec::new();
+    for dir in dirs {
+        match find_stale(dir) {
+            Ok(found) => stale.extend(found),
+            Err(cause)
+                if cause
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => {}
+            Err(cause) => return Err(cause.context(format!("failed to look in {}", dir.display()))),
+        }
+    }
+    Ok(stale)
+}
+
+/// Find the store directories in `dir` that no running sendme process uses.
+pub fn find_stale(dir: &Path) -> anyhow::Result<Vec<Stale>> {
+    let mut stale = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if !PREFIXES.iter().any(|prefix| name.starts_with(prefix)) || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        let modified = entry.metadata()?.modified().ok();
+        let pid = match File::open(path.join(LOCK_FILE)) {
+            Ok(mut file) => {
+                if !file.try_lock_exclusive()? {
+                    continue;
+                }
+                let mut text = String::new();
+                file.read_to_string(&mut text)?;
+                text.trim().parse().ok()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // the process that created it may not have locked it yet
+                let age = modified.and_then(|modified| modified.elapsed().ok());
+                if age.map_or(true, |age| age < LOCK_GRACE) {
+                    continue;
+                }
+                None
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let size = WalkDir::new(&path)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum();
+        stale.push(Stale {
+            path,
+            pid,
+            size,
+            modified,
+        });
+    }
+    stale.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(stale)
+}
+
+/// Print a list of stale store directories, the ones outside the current directory with their path.
+fn print_stale(stale: &[Stale]) {
+    let cwd = std::env::current_dir().ok();
+    for dir in stale {
+        let name = match dir.path.parent() == cwd.as_deref() {
+            true => dir.path.file_name().unwrap_or_default().to_string_lossy(),
+            false => dir.path.to_string_lossy(),
+        };
+        let mut details = vec![HumanBytes(dir.size).to_string()];
+        if dir.is_download() {
+            details.push("a partial download".to_string());
+        }
+        if let Some(pid) = dir.pid {
+            details.push(format!("left by process {pid}"));
+        }
+        if let Some(age) = dir.modified.and_then(|modified| modified.elapsed().ok()) {
+            details.push(format!("last used {} ago", HumanDuration(age)));
+        }
+        eprintln!("    {name} ({})", details.join(", "));
+    }
+}
+
+/// Ask whether to remove the stale directories, if stdin is a terminal.
+async fn confirm_remove(stale: &[Stale]) -> anyhow::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    if stale.iter().any(Stale::is_download) {
+        eprintln!(
+            "Partial downloads are resumed by receiving the same ticket again in this directory."
+        );
+    }
+    eprint!("Remove {} directories? [y/N] ", stale.len());
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map(|_| answer)
+    })
+    .await??;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Remove the stale directories, skipping the ones a process started to use since they were found.
+async fn remove(stale: &[Stale]) -> anyhow::Result<()> {
+    let mut removed = 0;
+    for dir in stale {
+        let lock = match lock(&dir.path) {
+            Ok(lock) => lock,
+            Err(cause) => {
+                eprintln!("not removing {}: {cause:#}", dir.path.display());
+                continue;
+            }
+        };
+        remove_contents(&dir.path)
+            .with_context(|| format!("failed to remove {}", dir.path.display()))?;
+        // the lock file goes last, some platforms can not remove files that are open
+        drop(lock);
+        tokio::fs::remove_dir_all(&dir.path)
+            .await
+            .with_context(|| format!("failed to remove {}", dir.path.display()))?;
+        removed += 1;
+    }
+    eprintln!("removed {removed} directories");
+    Ok(())
+}
+
+/// Remove everything in a store directory but its lock file.
+fn remove_contents(dir: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == LOCK_FILE {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Tell about the stale store directories when starting a transfer.
+///
+/// Looks everywhere stores are made, including `store_dir` if given. `keep` is
+/// a directory that is about to be used again, e.g. to resume a download.
+/// If `ask` is set and stdin is a terminal, offers to remove them.
+pub async fn check(keep: Option<&Path>, store_dir: Option<&Path>, ask: bool) {
+    let stale = match find_stale_in(&store_parents(store_dir)) {
+        Ok(stale) => stale,
+        Err(cause) => {
+            // not being able to look is no reason to stop the transfer
+            tracing::debug!("failed to look for stale directories: {cause}");
+            return;
+        }
+    };
+    let stale = stale
+        .into_iter()
+        .filter(|dir| Some(dir.path.as_path()) != keep)
+        .collect::<Vec<_>>();
+    if stale.is_empty() {
+        return;
+    }
+    eprintln!("found directories left behind by sendme processes that did not finish:");
+    print_stale(&stale);
+    if !ask || !std::io::stdin().is_terminal() {
+        eprintln!("use `sendme gc` to remove them");
+        return;
+    }
+    match confirm_remove(&stale).await {
+        Ok(true) => {
+            if let Err(cause) = remove(&stale).await {
+                eprintln!("{cause:#}");
+            }
+        }
+        Ok(false) => {}
+        Err(cause) => tracing::debug!("failed to ask about stale directories: {cause}"),
+    }
+}
+
+/// Remove the stale store directories in a directory, or everywhere stores are made.
+pub async fn run(args: GcArgs) -> anyhow::Result<()> {
+    let dirs = match args.dir {
+        Some(dir) => vec![dir],
+        None => store_parents(None),
+    };
+    let stale = find_stale_in(&dirs)?;
+    if stale.is_empty() {
+        let dirs = dirs
+            .iter()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<_>>();
+        eprintln!("no directories left behind in {}", dirs.join(", "));
+        return Ok(());
+    }
+    eprintln!("directories left behind by sendme processes that did not finish:");
+    print_stale(&stale);
+    if args.yes || confirm_remove(&stale).await? {
+        remove(&stale).await?;
+    } else if !std::io::stdin().is_terminal() {
+        eprintln!("use --yes to remove them");
+    }
+    Ok(())
+}
@@ -1,12 +1,23 @@
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::process::{Command, Child};
+use std::process::{Command, Child, ChildStdin, Stdio};
+use std::time::{Duration, Instant};
 use anyhow::Result;
 use eframe::{egui, App, CreationContext};
-use egui::{TextEdit, ScrollArea, RichText, TextStyle, Color32, Vec2, Frame};
+use egui::{
+    Color32, ColorImage, Frame, RichText, ScrollArea, TextEdit, TextStyle, TextureHandle, Vec2,
+};
 use egui::style::Margin;
-use std::io::BufRead;
+use std::io::{BufRead, Read, Write};
 use arboard::Clipboard;
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use iroh_blobs::ticket::BlobTicket;
+use std::str::FromStr;
+use crate::error::{SendmeError, Step};
+use crate::history;
+use crate::limit::Rate;
+use crate::mdns::{MdnsDiscovery, Peer};
 
 // Color palette
 struct AppColors;
@@ -23,24 +34,486 @@ impl AppColors {
     const SURFACE: Color32 = Color32::WHITE;                       // White for cards/panels
 }
 
+/// Progress event reported by a sendme child process on stderr, one JSON object per line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChildEvent {
+    /// Hashing the files to send
+    Import { done: u64, total: u64 },
+    /// Downloading the data
+    Download { done: u64, total: u64 },
+    /// A file in a collection, when only listing it
+    File { name: String, size: u64 },
+    /// The transfer failed, with the step that failed if known and the causes of the error
+    Failed {
+        step: Option<Step>,
+        message: String,
+        causes: Vec<String>,
+    },
+}
+
+impl ChildEvent {
+    /// The event for a transfer that failed with `error`
+    pub fn failed(error: &anyhow::Error) -> Self {
+        let step = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<SendmeError>())
+            .map(SendmeError::step);
+        Self::Failed {
+            step,
+            message: error.to_string(),
+            causes: error
+                .chain()
+                .skip(1)
+                .map(|cause| cause.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Command sent to a sendme child process on stdin, one JSON object per line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ChildCommand {
+    /// Change the bandwidth limit, or remove it
+    LimitRate { bytes_per_second: Option<u64> },
+}
+
+/// Reports [`ChildEvent`]s on stderr if enabled, at most ten times per second
+#[derive(Debug)]
+pub struct EventReporter {
+    enabled: bool,
+    last: Option<Instant>,
+}
+
+impl EventReporter {
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            last: None,
+        }
+    }
+
+    /// Report an event, unless another one was reported very recently
+    pub fn report(&mut self, event: ChildEvent) {
+        if self
+            .last
+            .map(|last| last.elapsed() >= Self::INTERVAL)
+            .unwrap_or(true)
+        {
+            self.report_now(event);
+        }
+    }
+
+    /// Report an event in any case, e.g. because it is the final one
+    pub fn report_now(&mut self, event: ChildEvent) {
+        if !self.enabled {
+            return;
+        }
+        if let Ok(json) = serde_json::to_string(&event) {
+            eprintln!("{}", json);
+        }
+        self.last = Some(Instant::now());
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum TransferKind {
+    Send,
+    Receive,
+}
+
+#[derive(PartialEq, Clone)]
+enum TransferStatus {
+    Running,
+    Finished,
+    Failed(String),
+    Stopped,
+}
+
+/// The part of a transfer that is updated by the threads reading the child process output
+struct TransferState {
+    status: TransferStatus,
+    log: String,
+    ticket: Option<String>, // The ticket, once a send is ready for receivers
+    progress: Option<ChildEvent>,
+    failure: Option<String>, // What went wrong, as reported by the child process
+    child: Option<Child>,    // The child process, while it is running
+    stdin: Option<ChildStdin>, // To send ChildCommands to the child process
+}
+
+/// A single send or receive, running as a sendme child process
+struct Transfer {
+    id: u64,
+    kind: TransferKind,
+    label: String,      // The path being sent or the ticket being received
+    to: Option<String>, // The name of the device a send goes straight to
+    limit_rate: String, // The bandwidth limit as entered, empty for none
+    state: Arc<Mutex<TransferState>>,
+    qr_code: Option<(String, TextureHandle)>, // QR code texture and the ticket it shows
+}
+
+impl Transfer {
+    /// Start a sendme child process, and threads to collect its output
+    ///
+    /// `arg` is the path to send or the ticket to receive. The child runs in `dir`,
+    /// or the current directory if not given. A send with a peer goes straight to it.
+    /// `limit_rate` is a valid bandwidth limit, or empty for none.
+    fn start(
+        id: u64,
+        kind: TransferKind,
+        arg: String,
+        dir: Option<PathBuf>,
+        to: Option<&Peer>,
+        limit_rate: String,
+    ) -> Self {
+        let state = Arc::new(Mutex::new(TransferState {
+            status: TransferStatus::Running,
+            log: String::new(),
+            ticket: None,
+            progress: None,
+            failure: None,
+            child: None,
+            stdin: None,
+        }));
+        let exe_path = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("sendme"));
+
+        let mut command = Command::new(exe_path);
+        command
+            .arg(match kind {
+                TransferKind::Send => "send",
+                TransferKind::Receive => "receive",
+            })
+            .arg(&arg)
+            .arg("--json-progress")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if !limit_rate.is_empty() {
+            command.arg("--limit-rate").arg(&limit_rate);
+        }
+        if kind == TransferKind::Send {
+            // the progress and the limit need the child to serve the data, even with
+            // `daemon = true` in the config file
+            command.arg("--no-daemon");
+        }
+        if kind == TransferKind::Receive {
+            // there is no terminal to confirm the download on
+            command.arg("--yes");
+        }
+        if let Some(peer) = to {
+            command
+                .arg("--to")
+                .arg(peer.node_id.to_string())
+                .arg("--local-discovery");
+        }
+        if let Some(dir) = dir {
+            command.current_dir(dir);
+        }
+
+        match command.spawn() {
+            Ok(mut child) => {
+                let stdout = child.stdout.take();
+                let stderr = child.stderr.take();
+                {
+                    let mut state = state.lock().unwrap();
+                    state.stdin = child.stdin.take();
+                    state.child = Some(child);
+                }
+
+                let stderr_thread = stderr.map(|stderr| {
+                    let state = state.clone();
+                    std::thread::spawn(move || read_output(stderr, &state))
+                });
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    if let Some(stdout) = stdout {
+                        read_output(stdout, &state);
+                    }
+                    // make sure all of stderr is in the log before looking at the exit status
+                    if let Some(thread) = stderr_thread {
+                        let _ = thread.join();
+                    }
+                    wait_for_exit(&state);
+                });
+            }
+            Err(e) => {
+                state.lock().unwrap().status =
+                    TransferStatus::Failed(format!("Failed to start sendme process: {}", e));
+            }
+        }
+
+        Self {
+            id,
+            kind,
+            label: arg,
+            to: to.map(|peer| peer.device.name.clone()),
+            limit_rate,
+            state,
+            qr_code: None,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.state.lock().unwrap().status == TransferStatus::Running
+    }
+
+    /// Tell the child process to change its bandwidth limit
+    fn send_command(&self, command: &ChildCommand) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let stdin = state
+            .stdin
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("the transfer is not running"))?;
+        writeln!(stdin, "{}", serde_json::to_string(command)?)?;
+        stdin.flush()?;
+        Ok(())
+    }
+
+    /// Kill the child process, if it is still running
+    fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stdin = None;
+        if let Some(mut child) = state.child.take() {
+            if let Err(e) = child.kill() {
+                eprintln!("Failed to kill process: {}", e);
+            }
+            let _ = child.wait();
+            state.status = TransferStatus::Stopped;
+        }
+    }
+}
+
+/// Collect the output of a child process into the transfer state, until it is closed
+fn read_output(output: impl Read, state: &Mutex<TransferState>) {
+    let reader = std::io::BufReader::new(output);
+    for line in reader.lines().map_while(Result::ok) {
+        let mut state = state.lock().unwrap();
+        if let Ok(event) = serde_json::from_str::<ChildEvent>(&line) {
+            match event {
+                ChildEvent::Failed {
+                    step,
+                    message,
+                    causes,
+                } => state.failure = Some(describe_failure(step, &message, &causes)),
+                event => state.progress = Some(event),
+            }
+            continue;
+        }
+        if line.starts_with("sendme receive ") {
+            state.ticket = Some(line.trim_start_matches("sendme receive ").to_string());
+        }
+        state.log = format!("{}\n{}", state.log, line);
+    }
+}
+
+/// Wait for the child process to exit and record how it went
+fn wait_for_exit(state: &Mutex<TransferState>) {
+    loop {
+        {
+            let mut state = state.lock().unwrap();
+            // the child is gone if the transfer was stopped
+            let Some(child) = state.child.as_mut() else {
+                return;
+            };
+            match child.try_wait() {
+                Ok(Some(exit)) => {
+                    state.child = None;
+                    state.status = if exit.success() {
+                        TransferStatus::Finished
+                    } else {
+                        // otherwise the last line of output is usually the error
+                        let message = state.failure.clone().unwrap_or_else(|| {
+                            state
+                                .log
+                                .lines()
+                                .last()
+                                .filter(|line| !line.is_empty())
+                                .map(ToOwned::to_owned)
+                                .unwrap_or_else(|| exit.to_string())
+                        });
+                        TransferStatus::Failed(message)
+                    };
+                    return;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    state.child = None;
+                    state.status = TransferStatus::Failed(e.to_string());
+                    return;
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// The contents of the collection of a ticket, listed by a `sendme receive --list` child process
+struct Preview {
+    ticket: String,
+    state: Arc<Mutex<PreviewState>>,
+}
+
+#[derive(Default)]
+struct PreviewState {
+    files: Vec<(String, u64)>, // Names and sizes of the files in the collection
+    done: bool,
+    error: Option<String>,
+    child: Option<Child>, // The child process, while it is running
+}
+
+impl Preview {
+    /// Start listing the collection of a ticket in the background
+    fn start(ticket: String) -> Self {
+        let state = Arc::new(Mutex::new(PreviewState::default()));
+        let exe_path = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("sendme"));
+        let mut command = Command::new(exe_path);
+        command
+            .arg("receive")
+            .arg(&ticket)
+            .arg("--list")
+            .arg("--json-progress")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                {
+                    let mut state = state.lock().unwrap();
+                    state.done = true;
+                    state.error = Some(format!("Failed to start sendme process: {}", e));
+                }
+                return Self { ticket, state };
+            }
+        };
+        let stderr = child.stderr.take();
+        state.lock().unwrap().child = Some(child);
+
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            let mut output = String::new();
+            if let Some(mut stderr) = stderr {
+                let _ = stderr.read_to_string(&mut output);
+            }
+            let mut state = thread_state.lock().unwrap();
+            state.done = true;
+            // the child is gone if the preview was dropped
+            let Some(mut child) = state.child.take() else {
+                return;
+            };
+            let status = match child.wait() {
+                Ok(status) => status,
+                Err(e) => {
+                    state.error = Some(format!("Failed to wait for sendme process: {}", e));
+                    return;
+                }
+            };
+            let mut last_line = String::new();
+            for line in output.lines() {
+                match serde_json::from_str::<ChildEvent>(line) {
+                    Ok(ChildEvent::File { name, size }) => state.files.push((name, size)),
+                    Ok(ChildEvent::Failed {
+                        step,
+                        message,
+                        causes,
+                    }) => last_line = describe_failure(step, &message, &causes),
+                    Ok(_) => {}
+                    Err(_) if !line.trim().is_empty() => last_line = line.to_string(),
+                    Err(_) => {}
+                }
+            }
+            if !status.success() {
+                state.error = Some(if last_line.is_empty() {
+                    status.to_string()
+                } else {
+                    last_line
+                });
+            }
+        });
+
+        Self { ticket, state }
+    }
+
+    fn is_running(&self) -> bool {
+        !self.state.lock().unwrap().done
+    }
+}
+
+impl Drop for Preview {
+    fn drop(&mut self) {
+        // Do not leave the child process running when the preview is no longer shown
+        if let Some(mut child) = self.state.lock().unwrap().child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Describe a failure reported by a child process, with a hint on what to do about it
+fn describe_failure(step: Option<Step>, message: &str, causes: &[String]) -> String {
+    let text = std::iter::once(message)
+        .chain(causes.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(": ");
+    let hint = match step {
+        Some(Step::Import) => "check that the files can be read",
+        Some(Step::Connect) => "check that the other device is online",
+        Some(Step::Request) => "the sender may have stopped sharing, ask for a new ticket",
+        Some(Step::Decode) => "the data is damaged or gone, try again or ask for a new ticket",
+        Some(Step::Export) => "check the files in the download folder",
+        None => return text,
+    };
+    format!("{} ({})", text, hint)
+}
+
+/// Extract a ticket from clipboard text, which may also be a whole `sendme receive` command line
+fn ticket_from_text(text: &str) -> Option<String> {
+    let text = text.split_ascii_whitespace().last()?;
+    BlobTicket::from_str(text).ok()?;
+    Some(text.to_string())
+}
+
+/// Parse a bandwidth limit as entered, empty means no limit
+fn parse_limit_rate(text: &str) -> Result<Option<Rate>> {
+    if text.is_empty() {
+        return Ok(None);
+    }
+    Rate::from_str(text).map(Some)
+}
+
+/// Something the user did with a transfer, applied after drawing the transfer list
+enum TransferAction {
+    Stop(u64),
+    Remove(u64),
+    Copy(String),
+    LimitRate(u64),
+}
+
 /// GUI application state
 pub struct SendmeApp {
     mode: AppMode,
     file_path: String,
     ticket: String,
     status: String,
-    output: Arc<Mutex<String>>,
-    extracted_ticket: Arc<Mutex<String>>,
-    is_ticket_ready: Arc<Mutex<bool>>,
-    command_running: Arc<Mutex<bool>>,
-    is_sending: bool, // Track if a sending session is active
-    child_process: Arc<Mutex<Option<Child>>>, // Store the child process
+    transfers: Vec<Transfer>, // All transfers of this session, oldest first
+    next_transfer_id: u64,
+    history: Vec<history::Entry>,       // Past transfers, oldest first
+    had_focus: bool,                    // Whether the window had focus in the last frame
+    clipboard_ticket: String, // The last ticket seen on the clipboard, so it is only offered once
+    clipboard_preview: Option<Preview>, // Contents of the ticket found on the clipboard
+    nearby: Option<Result<MdnsDiscovery, String>>, // Looks for devices on the local network, once the send tab was shown
+    limit_rate: String, // The bandwidth limit for new transfers, empty for none
 }
 
 #[derive(PartialEq, Clone, Copy)]
 enum AppMode {
     Send,
     Receive,
+    History,
 }
 
 impl SendmeApp {
@@ -50,12 +523,14 @@ impl SendmeApp {
             file_path: String::new(),
             ticket: String::new(),
             status: String::new(),
-            output: Arc::new(Mutex::new(String::new())),
-            extracted_ticket: Arc::new(Mutex::new(String::new())),
-            is_ticket_ready: Arc::new(Mutex::new(false)),
-            command_running: Arc::new(Mutex::new(false)),
-            is_sending: false,
-            child_process: Arc::new(Mutex::new(None)),
+            transfers: Vec::new(),
+            next_transfer_id: 0,
+            history: Vec::new(),
+            had_focus: false,
+            clipboard_ticket: String::new(),
+            clipboard_preview: None,
+            nearby: None,
+            limit_rate: String::new(),
         }
     }
 }
@@ -67,14 +542,641 @@ impl Default for SendmeApp {
             file_path: String::new(),
             ticket: String::new(),
             status: String::from("Ready"),
-            output: Arc::new(Mutex::new(String::new())),
-            extracted_ticket: Arc::new(Mutex::new(String::new())),
-            is_ticket_ready: Arc::new(Mutex::new(false)),
-            command_running: Arc::new(Mutex::new(false)),
-            is_sending: false,
-            child_process: Arc::new(Mutex::new(None)),
+            transfers: Vec::new(),
+            next_transfer_id: 0,
+            history: Vec::new(),
+            had_focus: false,
+            clipboard_ticket: String::new(),
+            clipboard_preview: None,
+            nearby: None,
+            limit_rate: String::new(),
+        }
+    }
+}
+
+impl SendmeApp {
+    /// Reload the transfer history from disk
+    fn load_history(&mut self) {
+        match history::load() {
+            Ok(entries) => self.history = entries,
+            Err(e) => self.status = format!("❌ Error: Failed to load history: {}", e),
+        }
+    }
+
+    /// Repeat a transfer from the history
+    fn repeat_transfer(&mut self, entry: history::Entry) {
+        match entry.direction {
+            history::Direction::Send => {
+                if let Some(path) = entry.path {
+                    self.mode = AppMode::Send;
+                    self.file_path = path.display().to_string();
+                    self.start_send();
+                }
+            }
+            history::Direction::Receive => {
+                if let Some(ticket) = entry.ticket {
+                    self.mode = AppMode::Receive;
+                    self.ticket = ticket.to_string();
+                    self.start_receive(entry.path.filter(|dir| dir.is_dir()));
+                }
+            }
+        }
+    }
+
+    /// Look for a ticket on the clipboard, and start listing its contents if it is a new one
+    fn check_clipboard(&mut self) {
+        let Ok(text) = Clipboard::new().and_then(|mut clipboard| clipboard.get_text()) else {
+            return;
+        };
+        let Some(ticket) = ticket_from_text(&text) else {
+            return;
+        };
+        if ticket == self.clipboard_ticket || ticket == self.ticket.trim() {
+            return;
+        }
+        self.clipboard_ticket = ticket.clone();
+        // don't offer to receive what we are sending ourselves
+        let own = self
+            .transfers
+            .iter()
+            .any(|t| t.state.lock().unwrap().ticket.as_deref() == Some(ticket.as_str()));
+        if !own {
+            self.clipboard_preview = Some(Preview::start(ticket));
+        }
+    }
+
+    /// Draw the ticket found on the clipboard, with the contents of its collection
+    fn clipboard_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(preview) = &self.clipboard_preview else {
+            return;
+        };
+        let mut receive = false;
+        let mut dismiss = false;
+        ui.group(|ui| {
+            ui.set_width(ui.available_width());
+            ui.horizontal(|ui| {
+                ui.heading(
+                    RichText::new("📋 Ticket found on clipboard")
+                        .size(18.0)
+                        .color(AppColors::TEXT_PRIMARY),
+                );
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if ui
+                        .add_sized(
+                            [80.0, 30.0],
+                            egui::Button::new(RichText::new("✖ Dismiss").size(14.0)),
+                        )
+                        .clicked()
+                    {
+                        dismiss = true;
+                    }
+                });
+            });
+
+            let state = preview.state.lock().unwrap();
+            if !state.done {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(
+                        RichText::new("Loading contents...")
+                            .size(14.0)
+                            .color(AppColors::TEXT_SECONDARY),
+                    );
+                });
+            } else if let Some(e) = &state.error {
+                ui.label(
+                    RichText::new(format!("❌ Error: {}", e))
+                        .size(14.0)
+                        .color(AppColors::DANGER),
+                );
+            } else {
+                const MAX_FILES: usize = 10;
+                for (name, size) in state.files.iter().take(MAX_FILES) {
+                    ui.label(
+                        RichText::new(format!("{}  ({})", name, HumanBytes(*size)))
+                            .size(14.0)
+                            .color(AppColors::TEXT_PRIMARY),
+                    );
+                }
+                if state.files.len() > MAX_FILES {
+                    ui.label(
+                        RichText::new(format!("... and {} more", state.files.len() - MAX_FILES))
+                            .size(14.0)
+                            .color(AppColors::TEXT_SECONDARY),
+                    );
+                }
+                let total = state.files.iter().map(|(_, size)| size).sum::<u64>();
+                ui.label(
+                    RichText::new(format!(
+                        "{} files, {} in total",
+                        state.files.len(),
+                        HumanBytes(total)
+                    ))
+                    .size(14.0)
+                    .color(AppColors::TEXT_SECONDARY),
+                );
+            }
+
+            let ready = state.done && state.error.is_none();
+            let receive_response = ui.add_enabled(
+                ready,
+                egui::Button::new(
+                    RichText::new("📥 Receive from clipboard")
+                        .size(14.0)
+                        .color(AppColors::TEXT_ON_COLOR)
+                        .strong(),
+                )
+                .fill(AppColors::PRIMARY),
+            );
+            if receive_response.clicked() {
+                receive = true;
+            }
+        });
+
+        if receive {
+            self.ticket = preview.ticket.clone();
+            self.clipboard_preview = None;
+            self.start_receive(None);
+        } else if dismiss {
+            self.clipboard_preview = None;
+        }
+    }
+
+    /// Start a new transfer and add it to the transfer list
+    fn start_transfer(
+        &mut self,
+        kind: TransferKind,
+        arg: String,
+        dir: Option<PathBuf>,
+        to: Option<&Peer>,
+    ) {
+        let limit_rate = self.limit_rate.trim().to_string();
+        if let Err(e) = parse_limit_rate(&limit_rate) {
+            self.status = format!("❌ Error: {}", e);
+            return;
+        }
+        let id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        self.transfers
+            .push(Transfer::start(id, kind, arg, dir, to, limit_rate));
+    }
+
+    /// Draw the bandwidth limit for new transfers
+    fn limit_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label(
+                RichText::new("Bandwidth limit:")
+                    .size(14.0)
+                    .color(AppColors::TEXT_SECONDARY),
+            );
+            ui.add(
+                TextEdit::singleline(&mut self.limit_rate)
+                    .desired_width(100.0)
+                    .hint_text("e.g. 10MB/s")
+                    .text_color(AppColors::TEXT_PRIMARY),
+            )
+            .on_hover_text("Limits the bandwidth of new transfers, leave empty for no limit");
+        });
+    }
+
+    /// Start sending the selected path
+    fn start_send(&mut self) {
+        let path = PathBuf::from(&self.file_path);
+        if !path.exists() {
+            self.status = format!("❌ Error: Path '{}' does not exist", self.file_path);
+            return;
+        }
+        self.status = format!("📤 Sending {}...", self.file_path);
+        self.start_transfer(TransferKind::Send, self.file_path.clone(), None, None);
+    }
+
+    /// Start sending the selected path straight to a device on the local network
+    fn start_send_to(&mut self, peer: &Peer) {
+        let path = PathBuf::from(&self.file_path);
+        if !path.exists() {
+            self.status = format!("❌ Error: Path '{}' does not exist", self.file_path);
+            return;
+        }
+        self.status = format!("📡 Sending {} to {}...", self.file_path, peer.device.name);
+        self.start_transfer(TransferKind::Send, self.file_path.clone(), None, Some(peer));
+    }
+
+    /// Draw the devices on the local network that wait for data, to send the selected path to
+    fn nearby_ui(&mut self, ui: &mut egui::Ui) {
+        let nearby = self
+            .nearby
+            .get_or_insert_with(|| MdnsDiscovery::browse().map_err(|e| e.to_string()));
+        let peers = match nearby {
+            Ok(nearby) => Ok(nearby
+                .peers()
+                .into_iter()
+                .filter(|peer| peer.device.listening)
+                .collect::<Vec<_>>()),
+            Err(e) => Err(e.clone()),
+        };
+        let mut send_to = None;
+        ui.group(|ui| {
+            ui.set_width(ui.available_width());
+            ui.heading(
+                RichText::new("📡 Nearby devices")
+                    .size(18.0)
+                    .color(AppColors::TEXT_PRIMARY)
+            );
+            match &peers {
+                Err(e) => {
+                    ui.label(
+                        RichText::new(format!("❌ Error: Local discovery is not available: {}", e))
+                            .size(14.0)
+                            .color(AppColors::DANGER)
+                    );
+                }
+                Ok(peers) if peers.is_empty() => {
+                    ui.label(
+                        RichText::new("No devices found. Run `sendme receive --listen --local-discovery` on the other device.")
+                            .size(14.0)
+                            .color(AppColors::TEXT_SECONDARY)
+                    );
+                }
+                Ok(peers) => {
+                    for peer in peers {
+                        ui.horizontal(|ui| {
+                            ui.label(
+                                RichText::new(&peer.device.name)
+                                    .size(15.0)
+                                    .color(AppColors::TEXT_PRIMARY)
+                                    .strong()
+                            );
+                            ui.label(
+                                RichText::new(peer.node_id.fmt_short())
+                                    .size(14.0)
+                                    .color(AppColors::TEXT_SECONDARY)
+                            );
+                            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                                let send_button = ui.add_enabled(
+                                    !self.file_path.is_empty(),
+                                    egui::Button::new(
+                                        RichText::new("📤 Send here")
+                                            .size(14.0)
+                                            .color(AppColors::TEXT_ON_COLOR)
+                                            .strong()
+                                    )
+                                    .fill(AppColors::PRIMARY)
+                                );
+                                if send_button.clicked() {
+                                    send_to = Some(peer.clone());
+                                }
+                                send_button.on_hover_text(format!("Send the selected file to {}", peer.device.name));
+                            });
+                        });
+                    }
+                }
+            }
+        });
+
+        if let Some(peer) = send_to {
+            self.start_send_to(&peer);
+        }
+    }
+
+    /// Start receiving the entered ticket
+    ///
+    /// The data is downloaded into `dir`, or the current directory if not given.
+    fn start_receive(&mut self, dir: Option<PathBuf>) {
+        self.status = "📥 Receiving file...".to_string();
+        self.start_transfer(
+            TransferKind::Receive,
+            self.ticket.trim().to_string(),
+            dir,
+            None,
+        );
+    }
+
+    /// Draw the list of transfers, newest first
+    fn transfers_ui(&mut self, ui: &mut egui::Ui) {
+        if self.transfers.is_empty() {
+            return;
+        }
+        ui.heading(
+            RichText::new("Transfers")
+                .size(18.0)
+                .color(AppColors::TEXT_PRIMARY),
+        );
+
+        let mut actions = Vec::new();
+        ScrollArea::vertical()
+            .id_source("transfers")
+            .show(ui, |ui| {
+                for transfer in self.transfers.iter_mut().rev() {
+                    ui.group(|ui| {
+                        ui.set_width(ui.available_width());
+                        transfer_ui(ui, transfer, &mut actions);
+                    });
+                }
+            });
+
+        for action in actions {
+            match action {
+                TransferAction::Stop(id) => {
+                    if let Some(transfer) = self.transfers.iter().find(|t| t.id == id) {
+                        transfer.stop();
+                        self.status = "⏹ Transfer stopped".to_string();
+                    }
+                }
+                TransferAction::Remove(id) => {
+                    self.transfers.retain(|t| t.id != id);
+                }
+                TransferAction::LimitRate(id) => {
+                    if let Some(transfer) = self.transfers.iter().find(|t| t.id == id) {
+                        let limit_rate = transfer.limit_rate.trim();
+                        self.status = match parse_limit_rate(limit_rate).and_then(|rate| {
+                            let command = ChildCommand::LimitRate {
+                                bytes_per_second: rate.map(|rate| rate.0),
+                            };
+                            transfer.send_command(&command).map(|_| rate)
+                        }) {
+                            Ok(Some(rate)) => format!("✅ Limited the transfer to {}", rate),
+                            Ok(None) => {
+                                "✅ Removed the bandwidth limit of the transfer".to_string()
+                            }
+                            Err(e) => format!("❌ Error: {}", e),
+                        };
+                    }
+                }
+                TransferAction::Copy(ticket) => match Clipboard::new() {
+                    Ok(mut clipboard) => {
+                        if clipboard.set_text(ticket).is_ok() {
+                            self.status = "✅ Ticket copied to clipboard".to_string();
+                        } else {
+                            self.status = "❌ Failed to copy to clipboard".to_string();
+                        }
+                    }
+                    Err(_) => {
+                        self.status = "❌ Clipboard not available".to_string();
+                    }
+                },
+            }
         }
     }
+
+    /// Draw the status message
+    fn status_ui(&self, ui: &mut egui::Ui) {
+        ui.label(
+            RichText::new(&self.status)
+                .size(14.0)
+                .color(if self.status.contains("Error") {
+                    AppColors::DANGER
+                } else if self.status.contains("✅") {
+                    AppColors::SUCCESS
+                } else {
+                    AppColors::TEXT_PRIMARY
+                }),
+        );
+    }
+}
+
+/// Draw a single transfer, collecting what the user did with it in `actions`
+fn transfer_ui(ui: &mut egui::Ui, transfer: &mut Transfer, actions: &mut Vec<TransferAction>) {
+    let (status, log, ticket, progress) = {
+        let state = transfer.state.lock().unwrap();
+        (
+            state.status.clone(),
+            state.log.clone(),
+            state.ticket.clone(),
+            state.progress.clone(),
+        )
+    };
+    let is_running = status == TransferStatus::Running;
+
+    ui.horizontal(|ui| {
+        let (icon, label) = match transfer.kind {
+            TransferKind::Send => match &transfer.to {
+                Some(name) => ("📤", format!("{} → {}", transfer.label, name)),
+                None => ("📤", transfer.label.clone()),
+            },
+            // tickets are long, only show the start
+            TransferKind::Receive => (
+                "📥",
+                format!("{}…", transfer.label.chars().take(24).collect::<String>()),
+            ),
+        };
+        ui.label(
+            RichText::new(format!("{} {}", icon, label))
+                .size(15.0)
+                .color(AppColors::TEXT_PRIMARY)
+                .strong(),
+        );
+
+        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+            if is_running {
+                let stop_button = ui.add_sized(
+                    [80.0, 30.0],
+                    egui::Button::new(
+                        RichText::new("⏹ Stop")
+                            .size(14.0)
+                            .color(AppColors::TEXT_ON_COLOR)
+                            .strong(),
+                    )
+                    .fill(AppColors::DANGER),
+                );
+                if stop_button.clicked() {
+                    actions.push(TransferAction::Stop(transfer.id));
+                }
+                stop_button.on_hover_text("Stop this transfer");
+            } else {
+                let remove_button = ui.add_sized(
+                    [80.0, 30.0],
+                    egui::Button::new(RichText::new("✖ Remove").size(14.0)),
+                );
+                if remove_button.clicked() {
+                    actions.push(TransferAction::Remove(transfer.id));
+                }
+                remove_button.on_hover_text("Remove this transfer from the list");
+            }
+        });
+    });
+
+    // Status line and progress
+    let (text, color) = match &status {
+        TransferStatus::Running if transfer.to.is_some() && progress.is_none() => {
+            ("⏳ Hashing files...".to_string(), AppColors::TEXT_PRIMARY)
+        }
+        TransferStatus::Running if transfer.to.is_some() => (
+            "📡 Waiting for the other device to download...".to_string(),
+            AppColors::TEXT_PRIMARY,
+        ),
+        TransferStatus::Finished if transfer.to.is_some() => (
+            format!(
+                "✅ Received by {}",
+                transfer.to.as_deref().unwrap_or_default()
+            ),
+            AppColors::SUCCESS,
+        ),
+        TransferStatus::Running => match (transfer.kind, &ticket, &progress) {
+            (TransferKind::Send, Some(_), _) => (
+                "📤 Sharing, waiting for receivers".to_string(),
+                AppColors::TEXT_PRIMARY,
+            ),
+            (TransferKind::Send, None, _) => {
+                ("⏳ Hashing files...".to_string(), AppColors::TEXT_PRIMARY)
+            }
+            (TransferKind::Receive, _, Some(_)) => {
+                ("📥 Downloading...".to_string(), AppColors::TEXT_PRIMARY)
+            }
+            (TransferKind::Receive, _, None) => {
+                ("⏳ Connecting...".to_string(), AppColors::TEXT_PRIMARY)
+            }
+        },
+        TransferStatus::Finished => ("✅ Done".to_string(), AppColors::SUCCESS),
+        TransferStatus::Failed(e) => (format!("❌ Error: {}", e), AppColors::DANGER),
+        TransferStatus::Stopped => ("⏹ Stopped".to_string(), AppColors::TEXT_SECONDARY),
+    };
+    ui.label(RichText::new(text).size(14.0).color(color));
+
+    // The bandwidth limit can be changed while the child process runs
+    if is_running {
+        ui.horizontal(|ui| {
+            ui.label(
+                RichText::new("Limit:")
+                    .size(14.0)
+                    .color(AppColors::TEXT_SECONDARY),
+            );
+            let response = ui.add(
+                TextEdit::singleline(&mut transfer.limit_rate)
+                    .desired_width(100.0)
+                    .hint_text("none")
+                    .text_color(AppColors::TEXT_PRIMARY),
+            );
+            let entered = response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+            let set_button = ui.button("Set");
+            if set_button.clicked() || entered {
+                actions.push(TransferAction::LimitRate(transfer.id));
+            }
+            set_button.on_hover_text(
+                "Change the bandwidth limit of this transfer, leave empty for no limit",
+            );
+        });
+    }
+
+    let show_progress = is_running && !(transfer.kind == TransferKind::Send && ticket.is_some());
+    if let (true, Some(ChildEvent::Import { done, total } | ChildEvent::Download { done, total })) =
+        (show_progress, progress)
+    {
+        let fraction = if total == 0 {
+            1.0
+        } else {
+            done as f32 / total as f32
+        };
+        ui.add(egui::ProgressBar::new(fraction).text(format!(
+            "{} / {}",
+            HumanBytes(done),
+            HumanBytes(total)
+        )));
+    }
+
+    // Ticket display section, for sends that are ready
+    let is_shared =
+        is_running || (transfer.kind == TransferKind::Send && status == TransferStatus::Finished);
+    if let (true, Some(ticket)) = (is_shared, ticket) {
+        ui.label("Share this ticket with the receiver:");
+        ui.horizontal(|ui| {
+            ui.add(
+                TextEdit::multiline(&mut ticket.clone())
+                    .desired_width(ui.available_width() - 100.0)
+                    .desired_rows(1)
+                    .font(TextStyle::Monospace)
+                    .text_color(AppColors::TEXT_PRIMARY)
+                    .frame(true)
+                    .margin(Vec2::new(8.0, 4.0)),
+            );
+
+            let copy_response = ui.add_sized(
+                [80.0, 30.0],
+                egui::Button::new(
+                    RichText::new("📋 Copy")
+                        .size(14.0)
+                        .color(AppColors::TEXT_ON_COLOR),
+                )
+                .fill(if ui.rect_contains_pointer(ui.min_rect()) {
+                    AppColors::PRIMARY_LIGHT
+                } else {
+                    AppColors::PRIMARY
+                }),
+            );
+
+            if copy_response.clicked() {
+                actions.push(TransferAction::Copy(ticket.clone()));
+            }
+
+            copy_response.on_hover_text("Copy the ticket to the clipboard");
+        });
+
+        // QR code of the ticket, only rebuilt when the ticket changes
+        if transfer
+            .qr_code
+            .as_ref()
+            .map(|(t, _)| t != &ticket)
+            .unwrap_or(true)
+        {
+            transfer.qr_code = qr_code_image(&ticket).map(|image| {
+                let texture = ui.ctx().load_texture(
+                    format!("ticket-qr-{}", transfer.id),
+                    image,
+                    egui::TextureFilter::Nearest,
+                );
+                (ticket.clone(), texture)
+            });
+        }
+        if let Some((_, texture)) = &transfer.qr_code {
+            egui::CollapsingHeader::new("QR code")
+                .id_source(("qr", transfer.id))
+                .default_open(true)
+                .show(ui, |ui| {
+                    ui.image(texture.id(), texture.size_vec2());
+                });
+        }
+    }
+
+    // Output of the child process
+    egui::CollapsingHeader::new("Log")
+        .id_source(("log", transfer.id))
+        .show(ui, |ui| {
+            ScrollArea::vertical()
+                .id_source(("log-scroll", transfer.id))
+                .max_height(150.0)
+                .show(ui, |ui| {
+                    ui.add(
+                        TextEdit::multiline(&mut log.as_str())
+                            .desired_width(f32::INFINITY)
+                            .desired_rows(6)
+                            .font(TextStyle::Monospace)
+                            .text_color(AppColors::TEXT_PRIMARY)
+                            .frame(true)
+                            .margin(Vec2::new(8.0, 4.0)),
+                    );
+                });
+        });
+}
+
+/// Render a ticket into a QR code image, with a quiet zone around it
+fn qr_code_image(ticket: &str) -> Option<ColorImage> {
+    const SCALE: usize = 4;
+    const QUIET_ZONE: usize = 4;
+    let (width, modules) = crate::qr::modules(ticket).ok()?;
+    let side = (width + 2 * QUIET_ZONE) * SCALE;
+    let mut image = ColorImage::new([side, side], Color32::WHITE);
+    for (i, dark) in modules.into_iter().enumerate() {
+        if !dark {
+            continue;
+        }
+        let x0 = (i % width + QUIET_ZONE) * SCALE;
+        let y0 = (i / width + QUIET_ZONE) * SCALE;
+        for y in y0..y0 + SCALE {
+            for x in x0..x0 + SCALE {
+                image.pixels[y * side + x] = Color32::BLACK;
+            }
+        }
+    }
+    Some(image)
 }
 
 impl App for SendmeApp {
@@ -95,6 +1197,27 @@ impl App for SendmeApp {
         style.visuals.widgets.active.fg_stroke.color = AppColors::TEXT_PRIMARY;
         ctx.set_style(style);
 
+        // Check the clipboard for a ticket whenever the window gains focus
+        let has_focus = ctx.input().raw.has_focus;
+        if has_focus && !self.had_focus {
+            self.check_clipboard();
+        }
+        self.had_focus = has_focus;
+
+        // Keep polling the child processes while something is running
+        if self.transfers.iter().any(Transfer::is_running)
+            || self
+                .clipboard_preview
+                .as_ref()
+                .map(Preview::is_running)
+                .unwrap_or(false)
+        {
+            ctx.request_repaint_after(Duration::from_millis(250));
+        } else if self.mode == AppMode::Send {
+            // devices come and go on the local network
+            ctx.request_repaint_after(Duration::from_secs(1));
+        }
+
         egui::CentralPanel::default()
             .frame(Frame::none()
                 .fill(AppColors::BACKGROUND)
@@ -109,30 +1232,20 @@ impl App for SendmeApp {
                         .color(AppColors::TEXT_PRIMARY)
                 );
                 ui.add_space(24.0);  // More space after the title
-                
-                // Get states
-                let is_running = *self.command_running.lock().unwrap();
-                let is_ticket_ready = *self.is_ticket_ready.lock().unwrap();
-                self.is_sending = is_running && is_ticket_ready;
-                
-                if self.is_sending && self.mode == AppMode::Receive {
-                    self.mode = AppMode::Send;
-                }
-                
+
                 // Mode selection tabs with modern styling
                 ui.horizontal(|ui| {
                     ui.add_space(4.0);  // Small indent for tabs
-                    
+
                     // Send tab
                     if ui.add(
                         egui::SelectableLabel::new(
                             self.mode == AppMode::Send,
-                            RichText::new("📤 Send")
-                                .size(16.0)
-                                .color(if self.mode == AppMode::Send { 
-                                    AppColors::PRIMARY 
-                                } else { 
-                                    AppColors::TEXT_SECONDARY 
+                            RichText::new("📤 Send").size(16.0).color(
+                                if self.mode == AppMode::Send {
+                                    AppColors::PRIMARY
+                                } else {
+                                    AppColors::TEXT_SECONDARY
                                 })
                         )
                     ).clicked() {
@@ -142,31 +1255,46 @@ impl App for SendmeApp {
                     ui.add_space(10.0);
 
                     // Receive tab
-                    let receive_response = ui.add_enabled(
-                        !self.is_sending,
-                        egui::SelectableLabel::new(
+                    if ui
+                        .add(egui::SelectableLabel::new(
                             self.mode == AppMode::Receive,
-                            RichText::new("📥 Receive")
-                                .size(16.0)
-                                .color(if !self.is_sending && self.mode == AppMode::Receive { 
-                                    AppColors::PRIMARY 
-                                } else { 
-                                    AppColors::DISABLED 
-                                })
-                        )
-                    );
-
-                    if receive_response.clicked() && !self.is_sending {
+                            RichText::new("📥 Receive").size(16.0).color(
+                                if self.mode == AppMode::Receive {
+                                    AppColors::PRIMARY
+                                } else {
+                                    AppColors::TEXT_SECONDARY
+                                },
+                            ),
+                        ))
+                        .clicked()
+                    {
                         self.mode = AppMode::Receive;
+                        self.check_clipboard();
                     }
-                    
-                    if self.is_sending {
-                        receive_response.on_hover_text("Cannot switch to Receive mode while a sending session is active");
+
+                    ui.add_space(10.0);
+
+                    // History tab
+                    if ui
+                        .add(egui::SelectableLabel::new(
+                            self.mode == AppMode::History,
+                            RichText::new("🕘 History").size(16.0).color(
+                                if self.mode == AppMode::History {
+                                    AppColors::PRIMARY
+                                } else {
+                                    AppColors::TEXT_SECONDARY
+                                },
+                            ),
+                        ))
+                        .clicked()
+                    {
+                        self.mode = AppMode::History;
+                        self.load_history();
                     }
                 });
-                
+
                 ui.add_space(20.0);
-                
+
                 match &self.mode {
                     AppMode::Send => {
                         // File selection section
@@ -185,7 +1313,7 @@ impl App for SendmeApp {
                                             .color(AppColors::TEXT_PRIMARY)
                                     );
                                     ui.add_space(12.0);
-                                    
+
                                     ui.horizontal(|ui| {
                                         ui.add_space(4.0);  // Small indent for input field
                                         ui.add(
@@ -196,7 +1324,7 @@ impl App for SendmeApp {
                                                 .frame(true)
                                                 .margin(Vec2::new(8.0, 4.0))
                                         );
-                                        
+
                                         let browse_response = ui.add_sized(
                                             [100.0, 30.0],
                                             egui::Button::new(
@@ -210,91 +1338,26 @@ impl App for SendmeApp {
                                                 AppColors::PRIMARY
                                             })
                                         );
-                                        
+
                                         if browse_response.clicked() {
                                             if let Some(path) = rfd::FileDialog::new().pick_file() {
                                                 self.file_path = path.display().to_string();
                                             }
                                         }
-                                        
+
                                         browse_response.on_hover_text("Browse for a file or directory");
                                     });
                                     ui.add_space(8.0);  // Bottom padding for group
                                 });
                             });
                         });
-                        
-                        ui.add_space(20.0);  // Space between major sections
-                        
-                        let is_ticket_ready = *self.is_ticket_ready.lock().unwrap();
-                        
-                        if is_ticket_ready {
-                            ui.add_space(20.0);
-                            // Ticket display section
-                            ui.group(|ui| {
-                                ui.set_min_height(120.0);
-                                ui.vertical(|ui| {
-                                    ui.add_space(5.0);
-                                    ui.heading(
-                                        RichText::new("🎟️ Your Transfer Ticket")
-                                            .size(18.0)
-                                            .color(AppColors::SUCCESS)
-                                    );
-                                    ui.label("Share this ticket with the receiver:");
-                                    
-                                    let ticket = self.extracted_ticket.lock().unwrap().clone();
-                                    
-                                    ui.add_space(5.0);
-                                    ui.horizontal(|ui| {
-                                        ui.add(
-                                            TextEdit::multiline(&mut ticket.clone())
-                                                .desired_width(ui.available_width() - 100.0)
-                                                .desired_rows(1)
-                                                .font(TextStyle::Monospace)
-                                                .text_color(AppColors::TEXT_PRIMARY)
-                                                .frame(true)
-                                                .margin(Vec2::new(8.0, 4.0))
-                                        );
-                                        
-                                        let copy_response = ui.add_sized(
-                                            [80.0, 30.0],
-                                            egui::Button::new(
-                                                RichText::new("📋 Copy")
-                                                    .size(14.0)
-                                                    .color(AppColors::TEXT_ON_COLOR)
-                                            )
-                                            .fill(if ui.rect_contains_pointer(ui.min_rect()) {
-                                                AppColors::PRIMARY_LIGHT
-                                            } else {
-                                                AppColors::PRIMARY
-                                            })
-                                        );
-                                        
-                                        if copy_response.clicked() {
-                                            match Clipboard::new() {
-                                                Ok(mut clipboard) => {
-                                                    if clipboard.set_text(ticket.clone()).is_ok() {
-                                                        self.status = "✅ Ticket copied to clipboard".to_string();
-                                                    } else {
-                                                        self.status = "❌ Failed to copy to clipboard".to_string();
-                                                    }
-                                                },
-                                                Err(_) => {
-                                                    self.status = "❌ Clipboard not available".to_string();
-                                                }
-                                            }
-                                        }
-                                        
-                                        copy_response.on_hover_text("Copy the ticket to the clipboard");
-                                    });
-                                });
-                            });
-                        }
-                        
-                        ui.add_space(20.0);
-                        
-                        // Send button section
-                        if !is_running {
+
+                        ui.add_space(8.0);
+
+                        // Status message and send button
+                        ui.horizontal(|ui| {
+                            self.status_ui(ui);
+
                             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                                 let send_button = ui.add_sized(
                                     [120.0, 40.0],
@@ -310,115 +1373,27 @@ impl App for SendmeApp {
                                         AppColors::DISABLED
                                     })
                                 );
-                                
-                                if send_button.clicked() && !self.file_path.is_empty() {
-                                    let path = PathBuf::from(&self.file_path);
-                                    if !path.exists() {
-                                        self.status = format!("❌ Error: Path '{}' does not exist", self.file_path);
-                                    } else {
-                                        self.status = format!("📤 Sending {}...", self.file_path);
-                                        *self.command_running.lock().unwrap() = true;
-                                        *self.is_ticket_ready.lock().unwrap() = false;
-                                        
-                                        // Clone all the necessary data before moving into the thread
-                                        let output = self.output.clone();
-                                        let extracted_ticket = self.extracted_ticket.clone();
-                                        let is_ticket_ready = self.is_ticket_ready.clone();
-                                        let path_clone = self.file_path.clone();
-                                        let child_process = self.child_process.clone();
-                                        let command_running = self.command_running.clone();
-                                        
-                                        *output.lock().unwrap() = String::new();
-                                        *extracted_ticket.lock().unwrap() = String::new();
-                                        
-                                        std::thread::spawn(move || {
-                                            let exe_path = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("sendme"));
-                                            
-                                            let mut child = Command::new(exe_path)
-                                                .arg("send")
-                                                .arg(path_clone)
-                                                .stdout(std::process::Stdio::piped())
-                                                .stderr(std::process::Stdio::piped())
-                                                .spawn()
-                                                .expect("Failed to start sendme process");
-                                            
-                                            let stdout = child.stdout.take();
-                                            *child_process.lock().unwrap() = Some(child);
-                                            
-                                            if let Some(stdout) = stdout {
-                                                let reader = std::io::BufReader::new(stdout);
-                                                for line in reader.lines() {
-                                                    if let Ok(line) = line {
-                                                        let mut out = output.lock().unwrap();
-                                                        *out = format!("{}\n{}", *out, line);
-                                                        
-                                                        if line.starts_with("sendme receive ") {
-                                                            let ticket = line.trim_start_matches("sendme receive ").to_string();
-                                                            *extracted_ticket.lock().unwrap() = ticket;
-                                                            *is_ticket_ready.lock().unwrap() = true;
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                            
-                                            *command_running.lock().unwrap() = false;
-                                        });
+
+                                    if send_button.clicked() && !self.file_path.is_empty() {
+                                        self.start_send();
                                     }
-                                }
-                                
+
                                 if !self.file_path.is_empty() {
                                     send_button.on_hover_text("Click to start sending the file");
                                 } else {
                                     send_button.on_hover_text("Please select a file first");
                                 }
                             });
-                        }
-                        
-                        // Status message and stop button
-                        ui.add_space(8.0);  // Space before status message
-                        ui.horizontal(|ui| {
-                            ui.label(
-                                RichText::new(&self.status)
-                                    .size(14.0)
-                                    .color(if self.status.contains("Error") {
-                                        AppColors::DANGER
-                                    } else if self.status.contains("✅") {
-                                        AppColors::SUCCESS
-                                    } else {
-                                        AppColors::TEXT_PRIMARY
-                                    })
-                            );
-
-                            // Add flexible space to push the stop button to the right
-                            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                                if is_running {
-                                    let stop_button = ui.add_sized(
-                                        [80.0, 30.0],
-                                        egui::Button::new(
-                                            RichText::new("⏹ Stop")
-                                                .size(14.0)
-                                                .color(AppColors::TEXT_ON_COLOR)
-                                                .strong()
-                                        )
-                                        .fill(AppColors::DANGER)
-                                    );
+                        });
 
-                                    if stop_button.clicked() {
-                                        if let Some(mut child) = self.child_process.lock().unwrap().take() {
-                                            let _ = child.kill();
-                                        }
-                                        *self.command_running.lock().unwrap() = false;
-                                        *self.is_ticket_ready.lock().unwrap() = false;
-                                        self.status = "⏹ Transfer stopped".to_string();
-                                    }
+                        ui.add_space(12.0);
+                        self.nearby_ui(ui);
 
-                                    stop_button.on_hover_text("Stop the current transfer");
-                                }
-                            });
-                        });
-                        ui.add_space(8.0);  // Bottom padding
+                        ui.add_space(12.0);
+                        self.limit_ui(ui);
+                        self.transfers_ui(ui);
                     }
-                    
+
                     AppMode::Receive => {
                         // Receive section
                         ui.group(|ui| {
@@ -431,17 +1406,49 @@ impl App for SendmeApp {
                                         .color(AppColors::TEXT_PRIMARY)
                                 );
                                 ui.add_space(12.0);
-                                
+
                                 ui.horizontal(|ui| {
                                     ui.add(
                                         TextEdit::singleline(&mut self.ticket)
-                                            .desired_width(ui.available_width() - 120.0)
+                                            .desired_width(ui.available_width() - 230.0)
                                             .hint_text("Paste the ticket here...")
                                             .text_color(AppColors::TEXT_PRIMARY)
                                             .frame(true)
                                             .margin(Vec2::new(8.0, 4.0))
                                     );
-                                    
+
+                                    let scan_response = ui.add_sized(
+                                        [100.0, 30.0],
+                                        egui::Button::new(
+                                            RichText::new("📷 Scan QR")
+                                                .size(14.0)
+                                                .color(AppColors::TEXT_ON_COLOR),
+                                        )
+                                        .fill(AppColors::PRIMARY),
+                                    );
+
+                                    if scan_response.clicked() {
+                                        if let Some(path) = rfd::FileDialog::new()
+                                            .add_filter("PNG image", &["png"])
+                                            .pick_file()
+                                        {
+                                            match crate::qr::decode_ticket_from_image(&path) {
+                                                Ok(ticket) => {
+                                                    self.ticket = ticket.to_string();
+                                                    self.status =
+                                                        "✅ Ticket read from QR code".to_string();
+                                                }
+                                                Err(e) => {
+                                                    self.status = format!("❌ Error: {}", e);
+                                                }
+                                            }
+                                        }
+                                    }
+
+                                    scan_response.on_hover_text(
+                                        "Read the ticket from a screenshot of a QR code",
+                                    );
+
                                     let receive_response = ui.add_sized(
                                         [100.0, 30.0],
                                         egui::Button::new(
@@ -460,42 +1467,11 @@ impl App for SendmeApp {
                                             AppColors::DISABLED
                                         })
                                     );
-                                    
+
                                     if receive_response.clicked() && !self.ticket.is_empty() {
-                                        self.status = "📥 Receiving file...".to_string();
-                                        *self.command_running.lock().unwrap() = true;
-                                        
-                                        let output = self.output.clone();
-                                        let command_running = self.command_running.clone();
-                                        let ticket = self.ticket.clone();
-                                        
-                                        std::thread::spawn(move || {
-                                            let exe_path = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("sendme"));
-                                            
-                                            if let Ok(mut child) = Command::new(exe_path)
-                                                .arg("receive")
-                                                .arg(&ticket)
-                                                .stdout(std::process::Stdio::piped())
-                                                .stderr(std::process::Stdio::piped())
-                                                .spawn()
-                                            {
-                                                if let Some(stdout) = child.stdout.take() {
-                                                    let reader = std::io::BufReader::new(stdout);
-                                                    for line in reader.lines() {
-                                                        if let Ok(line) = line {
-                                                            let mut out = output.lock().unwrap();
-                                                            *out = format!("{}\n{}", *out, line);
-                                                        }
-                                                    }
-                                                }
-                                                
-                                                let _ = child.wait();
-                                            }
-                                            
-                                            *command_running.lock().unwrap() = false;
-                                        });
+                                        self.start_receive(None);
                                     }
-                                    
+
                                     if !self.ticket.is_empty() {
                                         receive_response.on_hover_text("Click to start receiving the file");
                                     } else {
@@ -504,23 +1480,134 @@ impl App for SendmeApp {
                                 });
                             });
                         });
-                        
-                        // Output display
-                        ui.add_space(20.0);
-                        ScrollArea::vertical()
-                            .max_height(200.0)
-                            .show(ui, |ui| {
-                                let output = self.output.lock().unwrap();
-                                ui.add(
-                                    TextEdit::multiline(&mut output.as_str())
-                                        .desired_width(f32::INFINITY)
-                                        .desired_rows(10)
-                                        .font(TextStyle::Monospace)
-                                        .text_color(AppColors::TEXT_PRIMARY)
-                                        .frame(true)
-                                        .margin(Vec2::new(8.0, 4.0))
-                                );
-                            });
+
+                        ui.add_space(8.0);
+                        self.clipboard_ui(ui);
+
+                        // Status message
+                        ui.add_space(8.0);
+                        self.status_ui(ui);
+
+                        ui.add_space(12.0);
+                        self.limit_ui(ui);
+                        self.transfers_ui(ui);
+                    }
+
+                    AppMode::History => {
+                        ui.horizontal(|ui| {
+                            ui.heading(
+                                RichText::new("Transfer History")
+                                    .size(18.0)
+                                    .color(AppColors::TEXT_PRIMARY),
+                            );
+                            ui.with_layout(
+                                egui::Layout::right_to_left(egui::Align::Center),
+                                |ui| {
+                                    if ui.button("🔄 Refresh").clicked() {
+                                        self.load_history();
+                                    }
+                                },
+                            );
+                        });
+
+                        if self.history.is_empty() {
+                            ui.label(
+                                RichText::new("No transfers yet")
+                                    .size(14.0)
+                                    .color(AppColors::TEXT_SECONDARY),
+                            );
+                        }
+
+                        // The action is applied after the loop, since it needs to borrow self mutably
+                        let mut repeat = None;
+                        ScrollArea::vertical().show(ui, |ui| {
+                            for entry in self.history.iter().rev() {
+                                ui.group(|ui| {
+                                    ui.set_width(ui.available_width());
+                                    ui.horizontal(|ui| {
+                                        let icon = match entry.direction {
+                                            history::Direction::Send => "📤",
+                                            history::Direction::Receive => "📥",
+                                        };
+                                        ui.label(
+                                            RichText::new(format!("{} {}", icon, entry.name))
+                                                .size(15.0)
+                                                .color(AppColors::TEXT_PRIMARY)
+                                                .strong(),
+                                        );
+                                        ui.label(
+                                            RichText::new(HumanBytes(entry.size).to_string())
+                                                .size(14.0)
+                                                .color(AppColors::TEXT_SECONDARY),
+                                        );
+                                        let (outcome, color) = match &entry.outcome {
+                                            history::Outcome::Completed => {
+                                                ("✅ Completed".to_string(), AppColors::SUCCESS)
+                                            }
+                                            history::Outcome::Aborted => {
+                                                ("⏹ Aborted".to_string(), AppColors::TEXT_SECONDARY)
+                                            }
+                                            history::Outcome::Failed(e) => {
+                                                (format!("❌ Failed: {}", e), AppColors::DANGER)
+                                            }
+                                        };
+                                        ui.label(RichText::new(outcome).size(14.0).color(color));
+
+                                        ui.with_layout(
+                                            egui::Layout::right_to_left(egui::Align::Center),
+                                            |ui| {
+                                                let (label, available) = match entry.direction {
+                                                    history::Direction::Send => (
+                                                        "📤 Send again",
+                                                        entry
+                                                            .path
+                                                            .as_ref()
+                                                            .map(|p| p.exists())
+                                                            .unwrap_or(false),
+                                                    ),
+                                                    history::Direction::Receive => {
+                                                        ("📥 Re-download", entry.ticket.is_some())
+                                                    }
+                                                };
+                                                let response = ui.add_enabled(
+                                                    available,
+                                                    egui::Button::new(
+                                                        RichText::new(label).size(14.0),
+                                                    ),
+                                                );
+                                                if response.clicked() {
+                                                    repeat = Some(entry.clone());
+                                                }
+                                                response.on_disabled_hover_text(
+                                                    "The data is no longer available",
+                                                );
+                                            },
+                                        );
+                                    });
+                                    let peer = entry
+                                        .peer
+                                        .map(|peer| peer.fmt_short())
+                                        .unwrap_or_else(|| "unknown".to_string());
+                                    ui.label(
+                                        RichText::new(format!(
+                                            "{}  ·  hash {}  ·  peer {}",
+                                            entry
+                                                .time
+                                                .with_timezone(&chrono::Local)
+                                                .format("%Y-%m-%d %H:%M"),
+                                            entry.hash.fmt_short(),
+                                            peer,
+                                        ))
+                                        .size(12.0)
+                                        .color(AppColors::TEXT_SECONDARY),
+                                    );
+                                });
+                            }
+                        });
+
+                        if let Some(entry) = repeat {
+                            self.repeat_transfer(entry);
+                        }
                     }
                 }
             });
@@ -529,12 +1616,9 @@ impl App for SendmeApp {
 
 impl Drop for SendmeApp {
     fn drop(&mut self) {
-        // Terminate the process when the app is closed
-        if let Some(mut child) = self.child_process.lock().unwrap().take() {
-            // Try to kill the process gracefully
-            if let Err(e) = child.kill() {
-                eprintln!("Failed to kill process: {}", e);
-            }
+        // Terminate all child processes when the app is closed
+        for transfer in &self.transfers {
+            transfer.stop();
         }
     }
 }
@@ -552,7 +1636,7 @@ pub fn run_gui() -> Result<()> {
     eframe::run_native(
         "Sendme - Secure File Transfer",
         options,
-        Box::new(|cc| Box::new(SendmeApp::default())),
+        Box::new(|_cc| Box::new(SendmeApp::default())),
     );
 
     Ok(())
@@ -0,0 +1,176 @@
+//! Remembering the hashes of files that were sent, so that sending them again
+//! does not read them all again.
+//!
+//! With `--hash-cache`, `sendme send` uses a store in the data directory that is
+//! kept between sends, instead of a new one every time. The store refers to the
+//! files instead of copying them, and keeps their outboards. Next to it is a
+//! list of the files that were hashed, with their inode, size and modification
+//! time at that point. A file for which none of these changed, and whose hash is
+//! still complete in the store, is not hashed again.
+//!
+//! Before each send, the files that changed or are gone are forgotten, and
+//! everything in the store that is not the data of a remembered file is
+//! removed, including the collections of earlier sends. Only the process that
+//! has the cache open uses the store, so nothing else needs them.
+//!
+//! Removing the `hash-cache` directory in the data directory clears the cache.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use iroh_blobs::Hash;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{config, gc};
+
+/// The file with the hashes of the files, in the cache directory.
+const FILES: &str = "files.json";
+
+/// What identifies the content of a file without reading it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileId {
+    inode: u64,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl FileId {
+    fn new(metadata: &Metadata) -> Option<Self> {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Some(Self {
+            inode,
+            size: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+}
+
+/// The ids of all files in a file or directory, by path.
+///
+/// Two snapshots of the same path are equal if no file was added, removed or
+/// changed in between.
+pub fn snapshot(path: &Path) -> anyhow::Result<BTreeMap<PathBuf, FileId>> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(path) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let id = FileId::new(&entry.metadata()?)
+            .with_context(|| format!("no modification time for {}", entry.path().display()))?;
+        files.insert(entry.into_path(), id);
+    }
+    Ok(files)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedFile {
+    #[serde(flatten)]
+    id: FileId,
+    hash: Hash,
+}
+
+/// The hash cache, locked for this process while it is open.
+#[derive(Debug)]
+pub struct HashCache {
+    dir: PathBuf,
+    files: BTreeMap<PathBuf, CachedFile>,
+    _lock: gc::StoreLock,
+}
+
+impl HashCache {
+    /// Open the hash cache in the data directory.
+    ///
+    /// Fails if another sendme process is using it.
+    pub fn open() -> anyhow::Result<Self> {
+        let dir = config::data_dir()
+            .context("no data directory")?
+            .join("hash-cache");
+        let lock = gc::lock(&dir)?;
+        let path = dir.join(FILES);
+        let files = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|cause| {
+                tracing::warn!("ignoring invalid {}: {cause}", path.display());
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        Ok(Self {
+            dir,
+            files,
+            _lock: lock,
+        })
+    }
+
+    /// The directory of the store that is kept between sends.
+    pub fn store_dir(&self) -> PathBuf {
+        self.dir.join("store")
+    }
+
+    /// The hash of a file, if it did not change since it was hashed.
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> Option<Hash> {
+        let file = self.files.get(path)?;
+        (Some(&file.id) == FileId::new(metadata).as_ref()).then_some(file.hash)
+    }
+
+    /// Remember the hash of a file, as it was when `metadata` was read.
+    pub fn insert(&mut self, path: PathBuf, metadata: &Metadata, hash: Hash) {
+        match FileId::new(metadata) {
+            Some(id) => {
+                self.files.insert(path, CachedFile { id, hash });
+            }
+            None => {
+                self.files.remove(&path);
+            }
+        }
+    }
+
+    /// Forget the files that changed since they were hashed, and remove all
+    /// data from `store` that no remembered file has.
+    ///
+    /// The data of files sent by reference stays where it is, only the
+    /// outboards and the references are removed.
+    pub async fn prune(&mut self, store: &impl iroh_blobs::store::Store) -> anyhow::Result<()> {
+        self.files.retain(|path, file| {
+            let metadata = std::fs::metadata(path).ok();
+            metadata.as_ref().and_then(FileId::new).as_ref() == Some(&file.id)
+        });
+        let keep = self
+            .files
+            .values()
+            .map(|file| file.hash)
+            .collect::<BTreeSet<_>>();
+        let mut remove = Vec::new();
+        for hash in store.blobs().await?.chain(store.partial_blobs().await?) {
+            let hash = hash?;
+            if !keep.contains(&hash) {
+                remove.push(hash);
+            }
+        }
+        if !remove.is_empty() {
+            tracing::debug!("removing {} blobs from the hash cache", remove.len());
+            store.delete(remove).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the hashes of the files to disk.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = self.dir.join(FILES);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.files)?)?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Persisted history of past transfers.
+//!
+//! Every finished send or receive appends one line of JSON to a history file in
+//! the local data directory, so the GUI can show what was transferred after the
+//! process that did the transfer is long gone.
+
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use iroh::NodeId;
+use iroh_blobs::{ticket::BlobTicket, Hash};
+use serde::{Deserialize, Serialize};
+
+/// Whether we sent or received the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+/// How a transfer ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// All requested data was transferred.
+    Completed,
+    /// The other side went away before the transfer was done.
+    Aborted,
+    /// The transfer failed with an error.
+    Failed(String),
+}
+
+/// A single transfer in the history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the transfer ended.
+    pub time: DateTime<Utc>,
+    pub direction: Direction,
+    /// The name of the data, usually the top level file or directory name.
+    pub name: String,
+    /// The number of bytes transferred.
+    pub size: u64,
+    /// The hash of the collection.
+    pub hash: Hash,
+    /// The node id of the other side, if known.
+    pub peer: Option<NodeId>,
+    pub outcome: Outcome,
+    /// The path that was sent, or the directory the data was downloaded to.
+    pub path: Option<PathBuf>,
+    /// The ticket that was used to download the data.
+    pub ticket: Option<BlobTicket>,
+}
+
+impl Entry {
+    /// Create a new entry for the collection `hash`, with the time set to now.
+    pub fn new(direction: Direction, hash: Hash) -> Self {
+        Self {
+            time: Utc::now(),
+            direction,
+            name: hash.to_hex().to_string(),
+            size: 0,
+            hash,
+            peer: None,
+            outcome: Outcome::Completed,
+            path: None,
+            ticket: None,
+        }
+    }
+
+    /// Create a new entry for receiving the collection of a ticket into the current directory.
+    pub fn for_ticket(ticket: &BlobTicket) -> Self {
+        let mut entry = Self::new(Direction::Receive, ticket.hash());
+        entry.peer = Some(ticket.node_addr().node_id);
+        entry.ticket = Some(ticket.clone());
+        entry.path = std::env::current_dir().ok();
+        entry
+    }
+}
+
+/// The path of the history file, if there is a data directory on this platform.
+pub fn history_file() -> Option<PathBuf> {
+    Some(crate::config::data_dir()?.join("history.jsonl"))
+}
+
+/// Append an entry to the history file.
+pub fn append(entry: &Entry) -> anyhow::Result<()> {
+    let path = history_file().context("no data directory")?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    // a single write of the whole line, so concurrent sendme processes don't interleave
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?
+        .write_all(&line)?;
+    Ok(())
+}
+
+/// Append an entry to the history file, logging instead of failing on errors.
+///
+/// Not being able to write the history should never fail a transfer.
+pub fn record(entry: Entry) {
+    if let Err(cause) = append(&entry) {
+        tracing::warn!("failed to record transfer history: {cause}");
+    }
+}
+
+/// Load all entries from the history file, oldest first.
+///
+/// Lines that can not be parsed are skipped.
+pub fn load() -> anyhow::Result<Vec<Entry>> {
+    let path = history_file().context("no data directory")?;
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for line in std::io::BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(cause) => tracing::debug!("skipping invalid history line: {cause}"),
+        }
+    }
+    Ok(entries)
+}
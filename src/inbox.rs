@@ -0,0 +1,352 @@
+//! Receiving data that others push to us.
+//!
+//! This reverses the roles of `send` and `receive`. The inbox prints a ticket
+//! with just its own address. A sender runs `sendme push <ticket> <path>`,
+//! which imports the data like `send` does, connects to the inbox on a small
+//! custom protocol and announces a blob ticket for the data. The inbox then
+//! downloads the collection from the sender over the normal blobs protocol,
+//! exports it to its current directory and tells the sender how it went.
+//!
+//! `sendme receive --listen` runs the same protocol, but is found by its node
+//! id alone, so a sender can use `sendme send <path> --to <node-id>` without
+//! anyone copying a ticket. Since anybody who knows the node id can offer
+//! data, the listener asks before downloading, unless the sender is on the
+//! allow list.
+//!
+//! The inbox only ever downloads from the node that pushed the ticket, so
+//! it can not be used to make it fetch data from somewhere else.
+
+use std::{collections::BTreeSet, io::IsTerminal, path::PathBuf, sync::Arc, time::Duration};
+
+use data_encoding::HEXLOWER;
+use indicatif::HumanBytes;
+use iroh::{
+    endpoint::Connecting,
+    protocol::{ProtocolHandler, Router},
+    Endpoint, NodeAddr, NodeId,
+};
+use iroh_base::ticket::NodeTicket;
+use iroh_blobs::{net_protocol::Blobs, ticket::BlobTicket, BlobFormat};
+use n0_future::future::Boxed;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{error::SendmeError, history, limit::RateLimit, AddrInfoOptions, CommonArgs};
+
+/// The ALPN of the protocol used to push tickets to an inbox.
+pub const ALPN: &[u8] = b"/sendme/inbox/0";
+
+/// The maximum size of a pushed ticket or a response.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// The answer of the inbox to a pushed ticket, sent once the download is done.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum PushResponse {
+    /// The data was downloaded and exported.
+    Received,
+    /// The inbox could not get the data.
+    Failed { message: String },
+}
+
+/// Which pushes to accept without asking.
+#[derive(Debug, Clone)]
+pub enum Policy {
+    /// Accept all pushes, for an inbox whose ticket was handed out on purpose.
+    Inbox,
+    /// Accept pushes from the allowed nodes, and ask about all others.
+    Listen {
+        allow: BTreeSet<NodeId>,
+        /// Accept pushes from all nodes.
+        yes: bool,
+    },
+}
+
+impl Policy {
+    fn allows(&self, peer: &NodeId) -> bool {
+        match self {
+            Policy::Inbox => true,
+            Policy::Listen { allow, yes } => *yes || allow.contains(peer),
+        }
+    }
+}
+
+/// A pushed ticket, with the channel to send the outcome of the download to.
+type Push = (BlobTicket, oneshot::Sender<PushResponse>);
+
+/// Accepts pushed tickets and hands them to the inbox loop to download.
+///
+/// The download is not `Send`, so it can not run in the protocol handler.
+#[derive(Debug, Clone)]
+struct InboxProtocol {
+    pushes: mpsc::Sender<Push>,
+}
+
+impl ProtocolHandler for InboxProtocol {
+    fn accept(&self, conn: Connecting) -> Boxed<anyhow::Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let conn = conn.await?;
+            let peer = conn.remote_node_id()?;
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let ticket: BlobTicket =
+                serde_json::from_slice(&recv.read_to_end(MAX_MESSAGE_SIZE).await?)?;
+            let response = if ticket.node_addr().node_id != peer {
+                PushResponse::Failed {
+                    message: "the ticket must point to the pushing node".to_string(),
+                }
+            } else {
+                let (tx, rx) = oneshot::channel();
+                this.pushes.send((ticket, tx)).await?;
+                rx.await?
+            };
+            send.write_all(&serde_json::to_vec(&response)?).await?;
+            send.finish()?;
+            // the pusher closes the connection once it has read the response
+            conn.closed().await;
+            Ok(())
+        })
+    }
+}
+
+/// The response to a push that is rejected without downloading anything.
+///
+/// A push that the policy does not allow is rejected if there is nobody to ask.
+fn reject(ticket: &BlobTicket, policy: &Policy) -> Option<PushResponse> {
+    let peer = ticket.node_addr().node_id;
+    if policy.allows(&peer) || std::io::stdin().is_terminal() {
+        return None;
+    }
+    eprintln!("rejected data pushed by {peer}, it is not on the allow list");
+    Some(PushResponse::Failed {
+        message: "not on the allow list".to_string(),
+    })
+}
+
+/// Download the data of a pushed ticket, filling in the details of the history entry.
+///
+/// Asks for confirmation if the policy does not allow the sender.
+async fn receive(
+    endpoint: &Endpoint,
+    ticket: BlobTicket,
+    common: &CommonArgs,
+    policy: &Policy,
+    limit: &Arc<RateLimit>,
+    entry: &mut history::Entry,
+) -> PushResponse {
+    let peer = ticket.node_addr().node_id;
+    let allowed = policy.allows(&peer);
+    eprintln!("{peer} pushed {}", ticket.hash());
+    let retry = crate::RetryArgs::default();
+    let res = crate::download(endpoint, ticket, common, allowed, limit, &retry, entry).await;
+    match res {
+        Ok(()) => PushResponse::Received,
+        Err(e) => {
+            eprintln!("failed to receive data pushed by {peer}: {e}");
+            entry.outcome = history::Outcome::Failed(format!("{e:#}"));
+            PushResponse::Failed {
+                message: e.to_string(),
+            }
+        }
+    }
+}
+
+/// Run an inbox until ctrl-c is pressed.
+pub async fn run(common: CommonArgs, policy: Policy, limit: Arc<RateLimit>) -> anyhow::Result<()> {
+    let secret_key = crate::get_or_create_secret(common.verbose > 0)?;
+    let relay = common.relay_mode()?;
+    let mut builder = Endpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .secret_key(secret_key)
+        .relay_mode(relay.clone().into())
+        .transport_config(limit.transport_config());
+    if let Policy::Listen { .. } = policy {
+        // publish our address, so senders can find us by node id
+        builder = builder.add_discovery(common.pkarr_publisher());
+    }
+    if let Some(addr) = common.magic_ipv4_addr {
+        builder = builder.bind_addr_v4(addr);
+    }
+    if let Some(addr) = common.magic_ipv6_addr {
+        builder = builder.bind_addr_v6(addr);
+    }
+    if common.local_discovery {
+        let device = common.device(true);
+        builder = builder
+            .add_discovery(move |secret_key| crate::mdns::discovery(secret_key.public(), device));
+    }
+    let endpoint = builder.bind().await?;
+    let (pushes, mut incoming) = mpsc::channel(16);
+    let router = Router::builder(endpoint.clone())
+        .accept(ALPN, InboxProtocol { pushes })
+        .spawn()
+        .await?;
+
+    // wait for the endpoint to figure out its address before making a ticket
+    crate::wait_for_address(router.endpoint(), &relay).await?;
+    println!("receiving into {}", std::env::current_dir()?.display());
+    match policy {
+        Policy::Inbox => {
+            let ticket = NodeTicket::new(router.endpoint().node_addr().await?);
+            println!("to push data here, use");
+            println!("sendme push {ticket} <path>");
+        }
+        Policy::Listen { .. } => {
+            println!("to send data here, use");
+            println!("sendme send <path> --to {}", router.endpoint().node_id());
+        }
+    }
+
+    // pushes are downloaded one after the other, here since the download is
+    // not Send, but ctrl-c also stops a running one
+    loop {
+        let (ticket, tx) = tokio::select! {
+            Some(push) = incoming.recv() => push,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        if let Some(response) = reject(&ticket, &policy) {
+            tx.send(response).ok();
+            continue;
+        }
+        let mut entry = history::Entry::for_ticket(&ticket);
+        let response = tokio::select! {
+            response = receive(&endpoint, ticket, &common, &policy, &limit, &mut entry) => {
+                Some(response)
+            }
+            _ = tokio::signal::ctrl_c() => None,
+        };
+        let Some(response) = response else {
+            entry.outcome = history::Outcome::Failed("interrupted".to_string());
+            history::record(entry);
+            tx.send(PushResponse::Failed {
+                message: "the inbox was stopped".to_string(),
+            })
+            .ok();
+            break;
+        };
+        history::record(entry);
+        tx.send(response).ok();
+    }
+
+    println!("shutting down");
+    tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await??;
+    Ok(())
+}
+
+/// Push a file or directory to an inbox, serving it until the inbox has downloaded it.
+///
+/// If `addr` contains just the node id, its address is looked up via DNS.
+pub async fn push(
+    addr: NodeAddr,
+    path: PathBuf,
+    common: CommonArgs,
+    limit: Arc<RateLimit>,
+) -> anyhow::Result<()> {
+    let secret_key = crate::get_or_create_secret(common.verbose > 0)?;
+    let mut builder = Endpoint::builder()
+        .alpns(vec![iroh_blobs::protocol::ALPN.to_vec()])
+        .secret_key(secret_key)
+        .relay_mode(common.relay_mode()?.into());
+    if addr.relay_url.is_none() && addr.direct_addresses.is_empty() {
+        let dns = common.dns_discovery();
+        if common.local_discovery {
+            // a failing DNS lookup must not stop the local discovery
+            builder = builder.add_discovery(|_| Some(crate::mdns::IgnoreErrors(dns)));
+        } else {
+            builder = builder.add_discovery(|_| Some(dns));
+        }
+    }
+    if let Some(addr) = common.magic_ipv4_addr {
+        builder = builder.bind_addr_v4(addr);
+    }
+    if let Some(addr) = common.magic_ipv6_addr {
+        builder = builder.bind_addr_v6(addr);
+    }
+    if common.local_discovery {
+        let device = common.device(false);
+        builder = builder
+            .add_discovery(move |secret_key| crate::mdns::discovery(secret_key.public(), device));
+    }
+
+    let suffix = rand::thread_rng().gen::<[u8; 16]>();
+    let blobs_data_dir =
+        std::env::current_dir()?.join(format!(".sendme-push-{}", HEXLOWER.encode(&suffix)));
+    let lock = crate::gc::lock(&blobs_data_dir)?;
+    let res = push_from(builder, &blobs_data_dir, addr, path, &common, limit).await;
+    drop(lock);
+    tokio::fs::remove_dir_all(blobs_data_dir).await?;
+    res
+}
+
+/// Import the data into a store in `blobs_data_dir`, serve it and push a ticket for it.
+async fn push_from(
+    builder: iroh::endpoint::Builder,
+    blobs_data_dir: &std::path::Path,
+    to: NodeAddr,
+    path: PathBuf,
+    common: &CommonArgs,
+    limit: Arc<RateLimit>,
+) -> anyhow::Result<()> {
+    let endpoint = builder.bind().await?;
+    let ps = crate::SendStatus::new();
+    let status = ps.new_client();
+    let blobs = Blobs::persistent(blobs_data_dir)
+        .await?
+        .events(status.clone().into())
+        .build(&endpoint);
+    let protocol = crate::SendProtocol::new(blobs.clone(), status, limit);
+    let router = Router::builder(endpoint)
+        .accept(iroh_blobs::ALPN, protocol.clone())
+        .spawn()
+        .await?;
+
+    let (temp_tag, size, collection) = crate::import(
+        path.clone(),
+        blobs.store().clone(),
+        common.json_progress,
+        None,
+    )
+    .await?;
+    let hash = *temp_tag.hash();
+    let mut share = history::Entry::new(history::Direction::Send, hash);
+    share.name = crate::collection_name(&collection).unwrap_or(share.name);
+    share.path = path.canonicalize().ok();
+    *protocol.share.lock().unwrap() = Some(share);
+    println!("imported {}, {}", path.display(), HumanBytes(size));
+
+    crate::wait_for_address(router.endpoint(), &common.relay_mode()?).await?;
+    let mut addr = router.endpoint().node_addr().await?;
+    crate::apply_options(&mut addr, AddrInfoOptions::RelayAndAddresses);
+    let blob_ticket = BlobTicket::new(addr, hash, BlobFormat::HashSeq)?;
+
+    let inbox = to.node_id;
+    println!("pushing to {inbox}");
+    let conn = router
+        .endpoint()
+        .connect(to, ALPN)
+        .await
+        .map_err(|cause| SendmeError::connect(inbox, cause))?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&serde_json::to_vec(&blob_ticket)?).await?;
+    send.finish()?;
+    let response = recv.read_to_end(MAX_MESSAGE_SIZE).await?;
+    conn.close(0u32.into(), b"done");
+    drop(temp_tag);
+    // the inbox is done with us, so a slow shutdown is no reason to fail
+    if tokio::time::timeout(Duration::from_secs(2), router.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!("timed out shutting down");
+    }
+    match serde_json::from_slice(&response)? {
+        PushResponse::Received => {
+            println!("{inbox} received {}", path.display());
+            Ok(())
+        }
+        PushResponse::Failed { message } => {
+            anyhow::bail!("{inbox} did not receive the data: {message}")
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! The persistent secret key that gives this machine a stable node id.
+//!
+//! The key is stored as hex in a file in the config directory, readable only by
+//! the owner. Only one process at a time uses the stored key: two endpoints
+//! with the same node id would fight over the relay and the published
+//! address, so a ticket with just the node id would reach either of them.
+//! The first process locks the key until it exits, and processes started
+//! while it runs use a random key. This includes a process connecting to the
+//! one that uses the key, which could not connect to its own node id.
+//!
+//! The key file is written next to its final place and then renamed, so other
+//! processes never read a half written key. Writing it takes a lock, so two
+//! processes that create a key at the same time do not both succeed.
+
+use std::{fs::File, io::Write, path::PathBuf, str::FromStr, sync::OnceLock};
+
+use anyhow::Context;
+use fs4::fs_std::FileExt;
+use iroh::SecretKey;
+
+/// The path of the key file.
+pub fn key_file() -> anyhow::Result<PathBuf> {
+    Ok(crate::config::config_dir()
+        .context("no config directory")?
+        .join("secret.key"))
+}
+
+/// Load the stored key, if there is one.
+pub fn load() -> anyhow::Result<Option<SecretKey>> {
+    let path = key_file()?;
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let key = SecretKey::from_str(text.trim())
+        .with_context(|| format!("invalid secret key in {}", path.display()))?;
+    Ok(Some(key))
+}
+
+/// The lock on the stored key, held by the process using it until it exits.
+static IN_USE: OnceLock<File> = OnceLock::new();
+
+/// Load the stored key for this process, if there is one and no other process uses it.
+pub fn acquire() -> anyhow::Result<Option<SecretKey>> {
+    let Some(key) = load()? else {
+        return Ok(None);
+    };
+    if IN_USE.get().is_none() {
+        let file = File::create(key_file()?.with_extension("key.in-use"))?;
+        if !file.try_lock_exclusive()? {
+            return Ok(None);
+        }
+        IN_USE.set(file).ok();
+    }
+    Ok(Some(key))
+}
+
+/// Store a key, replacing an existing one only if `force` is set.
+pub fn store(key: &SecretKey, force: bool) -> anyhow::Result<PathBuf> {
+    let path = key_file()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let lock = File::create(path.with_extension("key.lock"))?;
+    lock.lock_exclusive()?;
+    if !force && path.exists() {
+        anyhow::bail!(
+            "a key is already stored in {}, use --force to replace it",
+            path.display()
+        );
+    }
+    let tmp = path.with_extension("key.tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(&tmp)
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    // the mode only applies to new files, so also fix up one left by a crash
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    writeln!(file, "{key}")?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(path)
+}
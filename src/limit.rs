@@ -0,0 +1,347 @@
+//! Limiting the bandwidth of transfers.
+//!
+//! The limit is shared by all connections of a process, so each connection
+//! that is transferring data gets an equal part of it. It can be changed while
+//! the transfer runs, which the GUI does by writing commands to the stdin of
+//! its sendme child processes.
+//!
+//! Sending is limited by reading the data to send no faster than the share of
+//! the connection, which works no matter how short the round trip is.
+//! Receiving is limited with QUIC flow control: many times per second, the
+//! sender is allowed to send as much data as the share of the connection adds up
+//! to since then.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    future::Future,
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use indicatif::HumanBytes;
+use iroh::endpoint::{Connection, TransportConfig, VarInt};
+use iroh_blobs::{
+    store::{bao_tree::io::fsm::Outboard, BaoBlobSize, Map, MapEntry},
+    Hash,
+};
+use iroh_io::AsyncSliceReader;
+use n0_future::task::AbortOnDropHandle;
+
+use crate::gui::ChildCommand;
+
+/// How long a connection counts as transferring after it last did.
+const ACTIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the receive window is adjusted.
+const ADJUST_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How far a sending connection that was idle may get ahead of its share.
+const SEND_BURST: Duration = Duration::from_millis(100);
+
+/// The receive window a connection starts with.
+///
+/// Flow control credit that was granted can not be taken back, so it must be
+/// small enough for a limit to take effect right away.
+const INITIAL_RECEIVE_WINDOW: u32 = 64 * 1024;
+
+/// The receive window without a limit, large enough for fast connections
+/// while still allowing a limit set later to take effect soon.
+const UNLIMITED_RECEIVE_WINDOW: u32 = 16 * 1024 * 1024;
+
+/// A transfer rate in bytes per second, like "10MB/s" or "512KiB".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_suffix("/s").unwrap_or(s);
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number = number
+            .parse::<f64>()
+            .with_context(|| format!("invalid rate {s}, expected something like 10MB/s"))?;
+        let unit = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1000,
+            "m" | "mb" => 1000 * 1000,
+            "g" | "gb" => 1000 * 1000 * 1000,
+            "kib" => 1024,
+            "mib" => 1024 * 1024,
+            "gib" => 1024 * 1024 * 1024,
+            unit => anyhow::bail!("unknown unit {unit}, use B, KB, MB, GB, KiB, MiB or GiB"),
+        };
+        let rate = (number * unit as f64) as u64;
+        anyhow::ensure!(rate > 0, "the rate must be more than 0");
+        Ok(Self(rate))
+    }
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", HumanBytes(self.0))
+    }
+}
+
+/// Which way data is transferred, each has the full limit.
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Send,
+    Receive,
+}
+
+/// The connections that are transferring data in one direction.
+#[derive(Debug, Default)]
+struct Active {
+    /// When each connection last transferred data, by an id of our own.
+    last: Mutex<BTreeMap<u64, Instant>>,
+    /// The number of connections in `last` that are still active, so that
+    /// reading it needs no lock.
+    count: AtomicU64,
+}
+
+/// The bandwidth limit of a process, shared by all its connections.
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    /// Bytes per second, or 0 for no limit.
+    rate: AtomicU64,
+    sending: Active,
+    receiving: Active,
+    next_id: AtomicU64,
+}
+
+impl RateLimit {
+    pub fn new(rate: Option<Rate>) -> Arc<Self> {
+        let limit = Arc::new(Self::default());
+        limit.set(rate);
+        limit
+    }
+
+    /// Change the limit, `None` removes it.
+    pub fn set(&self, rate: Option<Rate>) {
+        self.rate.store(
+            rate.map(|rate| rate.0).unwrap_or_default(),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn active(&self, direction: Direction) -> &Active {
+        match direction {
+            Direction::Send => &self.sending,
+            Direction::Receive => &self.receiving,
+        }
+    }
+
+    /// Record that a connection just transferred data, and forget the ones
+    /// that stopped.
+    ///
+    /// This takes a lock, so it must not be called for every packet.
+    fn touch(&self, direction: Direction, id: u64) {
+        // only needed to share a limit, so skip the lock without one
+        if self.rate.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let active = self.active(direction);
+        let mut last = active.last.lock().unwrap();
+        last.insert(id, Instant::now());
+        last.retain(|_, last| last.elapsed() < ACTIVE_TIMEOUT);
+        active.count.store(last.len() as u64, Ordering::Relaxed);
+    }
+
+    /// The part of the limit each transferring connection gets, if there is a limit.
+    fn share(&self, direction: Direction) -> Option<u64> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return None;
+        }
+        let count = self.active(direction).count.load(Ordering::Relaxed);
+        Some(rate / count.max(1))
+    }
+
+    /// The transport config for an endpoint whose downloads are limited.
+    pub fn transport_config(&self) -> TransportConfig {
+        let mut config = TransportConfig::default();
+        // the same as the endpoint uses by default
+        config.keep_alive_interval(Some(Duration::from_secs(1)));
+        config.receive_window(INITIAL_RECEIVE_WINDOW.into());
+        config
+    }
+
+    /// Limit the data sent on one connection from `store`.
+    ///
+    /// Serve the connection from the returned store.
+    pub fn limit_send<S>(self: &Arc<Self>, store: S) -> LimitedStore<S> {
+        LimitedStore {
+            inner: store,
+            pacer: Arc::new(Pacer {
+                limit: self.clone(),
+                id: self.next_id(),
+                next: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Limit the data received on a connection, until the handle is dropped.
+    pub fn limit_receive(self: &Arc<Self>, connection: Connection) -> AbortOnDropHandle<()> {
+        let limit = self.clone();
+        let id = limit.next_id();
+        let task = tokio::spawn(async move {
+            let mut received = connection.stats().udp_rx.bytes;
+            // what the connection may still receive, like a token bucket
+            let mut allowance = Some(f64::from(INITIAL_RECEIVE_WINDOW));
+            loop {
+                if limit.share(Direction::Receive).is_none() && allowance.take().is_some() {
+                    connection.set_receive_window(UNLIMITED_RECEIVE_WINDOW.into());
+                }
+                tokio::time::sleep(ADJUST_INTERVAL).await;
+                let total = connection.stats().udp_rx.bytes;
+                if total > received {
+                    limit.touch(Direction::Receive, id);
+                }
+                let delta = (total - received) as f64;
+                received = total;
+                let Some(share) = limit.share(Direction::Receive) else {
+                    continue;
+                };
+                let refill = share as f64 * ADJUST_INTERVAL.as_secs_f64();
+                // a connection that was idle may only catch up a little
+                let next = (allowance.unwrap_or(0.0) - delta + refill).min(2.0 * refill);
+                allowance = Some(next);
+                // the window is counted from the data the application has read,
+                // which on a fast path is all of it, so a window of 0 pauses the sender
+                let window = next.max(0.0) as u64;
+                connection.set_receive_window(VarInt::from_u64(window).unwrap_or(VarInt::MAX));
+            }
+        });
+        AbortOnDropHandle::new(task)
+    }
+
+    /// Follow the commands of the GUI on stdin, until it is closed.
+    pub fn follow_commands(self: &Arc<Self>) {
+        let limit = self.clone();
+        // a blocking read on stdin can not be cancelled, and must not keep the runtime from shutting down
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines().map_while(Result::ok) {
+                match serde_json::from_str::<ChildCommand>(&line) {
+                    Ok(ChildCommand::LimitRate { bytes_per_second }) => {
+                        limit.set(bytes_per_second.map(Rate));
+                    }
+                    Err(cause) => tracing::warn!("invalid command {line}: {cause}"),
+                }
+            }
+        });
+    }
+}
+
+/// Spaces out the reads of the data sent on one connection.
+#[derive(Debug)]
+struct Pacer {
+    limit: Arc<RateLimit>,
+    id: u64,
+    /// When the data read so far may be sent, at the share of the connection.
+    next: Mutex<Option<Instant>>,
+}
+
+impl Pacer {
+    /// Wait until `len` more bytes may be sent.
+    async fn wait(&self, len: usize) {
+        self.limit.touch(Direction::Send, self.id);
+        let Some(share) = self.limit.share(Direction::Send) else {
+            *self.next.lock().unwrap() = None;
+            return;
+        };
+        let until = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            // a connection that was idle may only catch up a little
+            let start = next.map_or(now, |next| next.max(now - SEND_BURST));
+            let until = start + Duration::from_secs_f64(len as f64 / share as f64);
+            *next = Some(until);
+            until
+        };
+        tokio::time::sleep_until(until.into()).await;
+    }
+}
+
+/// A store whose data is read no faster than the share of one connection.
+#[derive(Debug, Clone)]
+pub struct LimitedStore<S> {
+    inner: S,
+    pacer: Arc<Pacer>,
+}
+
+impl<S: Map> Map for LimitedStore<S> {
+    type Entry = LimitedEntry<S::Entry>;
+
+    async fn get(&self, hash: &Hash) -> io::Result<Option<Self::Entry>> {
+        let entry = self.inner.get(hash).await?;
+        Ok(entry.map(|inner| LimitedEntry {
+            inner,
+            pacer: self.pacer.clone(),
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitedEntry<E> {
+    inner: E,
+    pacer: Arc<Pacer>,
+}
+
+impl<E: MapEntry> MapEntry for LimitedEntry<E> {
+    fn hash(&self) -> Hash {
+        self.inner.hash()
+    }
+
+    fn size(&self) -> BaoBlobSize {
+        self.inner.size()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.inner.is_complete()
+    }
+
+    fn outboard(&self) -> impl Future<Output = io::Result<impl Outboard>> + Send {
+        self.inner.outboard()
+    }
+
+    async fn data_reader(&self) -> io::Result<impl AsyncSliceReader> {
+        Ok(LimitedReader {
+            inner: self.inner.data_reader().await?,
+            pacer: self.pacer.clone(),
+        })
+    }
+}
+
+struct LimitedReader<R> {
+    inner: R,
+    pacer: Arc<Pacer>,
+}
+
+impl<R: AsyncSliceReader> AsyncSliceReader for LimitedReader<R> {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Bytes> {
+        let data = self.inner.read_at(offset, len).await?;
+        self.pacer.wait(data.len()).await;
+        Ok(data)
+    }
+
+    async fn size(&mut self) -> io::Result<u64> {
+        self.inner.size().await
+    }
+}
@@ -3,19 +3,24 @@
 //! Command line arguments.
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fmt::{Display, Formatter},
+    fs::Metadata,
+    io::{self, IsTerminal},
     net::{SocketAddrV4, SocketAddrV6},
     path::{Component, Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
 use anyhow::Context;
 use clap::{
-    error::{ContextKind, ErrorKind},
-    CommandFactory, Parser, Subcommand,
+    error::{ContextKind, ContextValue, ErrorKind},
+    CommandFactory, FromArgMatches, Parser, Subcommand,
 };
 use console::style;
 use data_encoding::HEXLOWER;
@@ -25,38 +30,56 @@ use indicatif::{
 };
 use iroh::{
     discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher},
-    Endpoint, NodeAddr, RelayMap, RelayMode, RelayUrl, SecretKey,
+    endpoint::{Connecting, Connection, ConnectionError},
+    protocol::ProtocolHandler,
+    Endpoint, NodeAddr, NodeId, RelayMap, RelayMode, RelayUrl, SecretKey,
 };
+use iroh_base::ticket::NodeTicket;
 use iroh_blobs::{
     format::collection::Collection,
     get::{
         db::DownloadProgress,
-        fsm::{AtBlobHeaderNextError, DecodeError},
+        error::GetError,
+        fsm::{self, AtBlobHeaderNextError, DecodeError},
         request::get_hash_seq_and_sizes,
     },
     net_protocol::Blobs,
+    protocol::{GetRequest, RangeSpecSeq},
     provider::{self, CustomEventSender},
-    store::{ExportMode, ImportMode, ImportProgress},
+    store::{bao_tree::ChunkRanges, EntryStatus, ExportMode, ImportMode, ImportProgress},
     ticket::BlobTicket,
     BlobFormat, Hash, HashAndFormat, TempTag,
 };
 use n0_future::{future::Boxed, StreamExt};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use url::Url;
 use walkdir::WalkDir;
 
 /// Send a file or directory between two machines, using blake3 verified streaming.
 ///
 /// For all subcommands, you can specify a secret key using the IROH_SECRET
-/// environment variable. If you don't, a random one will be generated.
+/// environment variable. If you don't, the key stored with `sendme key generate`
+/// is used, or a random one will be generated if there is none. Only one sendme
+/// process at a time uses the stored key, the others get a random one.
 ///
 /// You can also specify a port for the magicsocket. If you don't, a random one
 /// will be chosen.
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[command(version, about, after_long_help = exit::HELP)]
 pub struct Args {
     #[clap(subcommand)]
     pub command: Commands,
+
+    /// Read defaults for options from this TOML file.
+    ///
+    /// Defaults to config.toml in the sendme config directory.
+    #[clap(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Use the options of a profile from the config file.
+    #[clap(long, global = true)]
+    pub profile: Option<String>,
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -102,10 +125,164 @@ pub enum Commands {
     /// Receive a file or directory.
     Receive(ReceiveArgs),
 
+    /// Manage the secret key that identifies this machine.
+    Key(KeyArgs),
+
+    /// Run in the background, sharing data sent with `sendme send --daemon` until stopped.
+    Daemon(DaemonArgs),
+
+    /// Manage the data shared by the daemon.
+    Shares(SharesArgs),
+
+    /// List the data shared by the daemon, like `sendme shares list`.
+    Ls,
+
+    /// Stop sharing data on the daemon, like `sendme shares remove`.
+    Stop(StopArgs),
+
+    /// Receive data pushed by others with `sendme push`, until stopped.
+    Inbox(InboxArgs),
+
+    /// Send a file or directory to an inbox.
+    Push(PushArgs),
+
+    /// List the sendme nodes on the local network that use `--local-discovery`.
+    Nearby(NearbyArgs),
+
+    /// Connect to the sender of a ticket and report how the connection works.
+    Doctor(DoctorArgs),
+
+    /// Remove the directories left behind by sendme processes that did not finish.
+    Gc(GcArgs),
+
     /// Launch GUI mode
     Gui,
 }
 
+#[derive(Parser, Debug)]
+pub struct InboxArgs {
+    #[clap(flatten)]
+    pub common: CommonArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct PushArgs {
+    /// The ticket printed by `sendme inbox`.
+    pub ticket: NodeTicket,
+
+    /// Path to the file or directory to push.
+    pub path: PathBuf,
+
+    #[clap(flatten)]
+    pub common: CommonArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct NearbyArgs {
+    /// How long to look for nodes, e.g. "5s".
+    #[clap(long, default_value = "3s")]
+    pub timeout: humantime::Duration,
+}
+
+#[derive(Parser, Debug)]
+pub struct DoctorArgs {
+    /// The ticket of the sender to connect to.
+    pub ticket: BlobTicket,
+
+    #[clap(flatten)]
+    pub common: CommonArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct GcArgs {
+    /// The directory to look in, defaults to the current and the runtime directory.
+    ///
+    /// Stores made with `sendme send --store-dir` are only found by giving that directory.
+    pub dir: Option<PathBuf>,
+
+    /// Remove the directories without asking.
+    #[clap(short, long)]
+    pub yes: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct DaemonArgs {
+    #[clap(flatten)]
+    pub common: CommonArgs,
+}
+
+#[derive(Parser, Debug)]
+pub struct SharesArgs {
+    #[clap(subcommand)]
+    pub command: SharesCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SharesCommand {
+    /// List the shares, with their tickets and about how often they were downloaded.
+    ///
+    /// Resumed and partial downloads are not counted.
+    List,
+
+    /// Share a file or directory.
+    Add(ShareAddArgs),
+
+    /// Stop sharing.
+    Remove(StopArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct ShareAddArgs {
+    /// Path to the file or directory to share.
+    pub path: PathBuf,
+
+    /// What type of ticket to use, see `sendme send --help`.
+    #[clap(long, default_value_t = AddrInfoOptions::RelayAndAddresses)]
+    pub ticket_type: AddrInfoOptions,
+
+    /// Stop sharing after this time, e.g. "2h" or "3days".
+    #[clap(long)]
+    pub expire: Option<humantime::Duration>,
+}
+
+#[derive(Parser, Debug)]
+pub struct StopArgs {
+    /// The hash of the collection to stop sharing, as shown by `sendme ls`.
+    pub hash: Hash,
+}
+
+#[derive(Parser, Debug)]
+pub struct KeyArgs {
+    #[clap(subcommand)]
+    pub command: KeyCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyCommand {
+    /// Generate a new key and store it.
+    Generate {
+        /// Replace an existing key.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Show the node id of the stored key.
+    Show,
+
+    /// Store an existing secret key, e.g. one exported on another machine.
+    Import {
+        /// The secret key in hex. Read from stdin if not given.
+        key: Option<String>,
+
+        /// Replace an existing key.
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Print the stored secret key.
+    Export,
+}
+
 #[derive(Parser, Debug)]
 pub struct CommonArgs {
     /// The IPv4 address that magicsocket will listen on.
@@ -131,9 +308,84 @@ pub struct CommonArgs {
     /// The relay URL to use as a home relay,
     ///
     /// Can be set to "disable" to disable relay servers and "default"
-    /// to configure default servers.
-    #[clap(long, default_value_t = RelayModeOption::Default)]
-    pub relay: RelayModeOption,
+    /// to configure default servers. Give it multiple times, or separate
+    /// the URLs with commas, to use several custom relay servers.
+    #[clap(long, default_value = "default", action = clap::ArgAction::Append, value_delimiter = ',')]
+    pub relay: Vec<RelayModeOption>,
+
+    /// The domain to look up node ids in via DNS, instead of the default one.
+    ///
+    /// This is where a `--pkarr-relay` publishes to.
+    #[clap(long)]
+    pub dns_origin: Option<String>,
+
+    /// The pkarr relay to publish our address to, for lookups by node id,
+    /// instead of the default one.
+    #[clap(long)]
+    pub pkarr_relay: Option<Url>,
+
+    /// Announce this node and find others on the local network with mDNS.
+    ///
+    /// This makes tickets with just a node id work without internet access.
+    #[clap(long)]
+    pub local_discovery: bool,
+
+    /// The name other devices on the local network see, with `--local-discovery`.
+    ///
+    /// Defaults to the host name.
+    #[clap(long)]
+    pub device_name: Option<String>,
+
+    /// Report progress as JSON lines on stderr. Used by the GUI.
+    #[clap(long, hide = true)]
+    pub json_progress: bool,
+}
+
+impl CommonArgs {
+    /// How this node describes itself to others on the local network.
+    pub fn device(&self, listening: bool) -> mdns::Device {
+        mdns::Device::new(self.device_name.clone(), listening)
+    }
+
+    /// The relay mode, combining all `--relay` options.
+    pub fn relay_mode(&self) -> anyhow::Result<RelayModeOption> {
+        let mut urls = Vec::new();
+        for relay in &self.relay {
+            match relay {
+                RelayModeOption::Custom(custom) => {
+                    for url in custom {
+                        if !urls.contains(url) {
+                            urls.push(url.clone());
+                        }
+                    }
+                }
+                mode if self.relay.len() == 1 => return Ok(mode.clone()),
+                mode => anyhow::bail!("--relay {mode} can not be combined with other relays"),
+            }
+        }
+        Ok(RelayModeOption::Custom(urls))
+    }
+
+    /// Looks up node ids via DNS, in the `--dns-origin` domain if given.
+    pub fn dns_discovery(&self) -> DnsDiscovery {
+        match &self.dns_origin {
+            Some(origin) => DnsDiscovery::new(origin.clone()),
+            None => DnsDiscovery::n0_dns(),
+        }
+    }
+
+    /// Publishes our address for lookups by node id, to the `--pkarr-relay` if given.
+    pub fn pkarr_publisher(
+        &self,
+    ) -> impl FnOnce(&SecretKey) -> Option<PkarrPublisher> + Send + Sync + 'static {
+        let pkarr_relay = self.pkarr_relay.clone();
+        move |secret_key| {
+            Some(match pkarr_relay {
+                Some(url) => PkarrPublisher::new(secret_key.clone(), url),
+                None => PkarrPublisher::n0_dns(secret_key.clone()),
+            })
+        }
+    }
 }
 
 /// Available command line options for configuring relays.
@@ -143,8 +395,8 @@ pub enum RelayModeOption {
     Disabled,
     /// Uses the default relay servers.
     Default,
-    /// Uses a single, custom relay server by URL.
-    Custom(RelayUrl),
+    /// Uses custom relay servers by URL.
+    Custom(Vec<RelayUrl>),
 }
 
 impl FromStr for RelayModeOption {
@@ -154,7 +406,7 @@ impl FromStr for RelayModeOption {
         match s {
             "disabled" => Ok(Self::Disabled),
             "default" => Ok(Self::Default),
-            _ => Ok(Self::Custom(RelayUrl::from_str(s)?)),
+            _ => Ok(Self::Custom(vec![RelayUrl::from_str(s)?])),
         }
     }
 }
@@ -164,7 +416,10 @@ impl Display for RelayModeOption {
         match self {
             Self::Disabled => f.write_str("disabled"),
             Self::Default => f.write_str("default"),
-            Self::Custom(url) => url.fmt(f),
+            Self::Custom(urls) => {
+                let urls = urls.iter().map(ToString::to_string).collect::<Vec<_>>();
+                f.write_str(&urls.join(","))
+            }
         }
     }
 }
@@ -174,7 +429,12 @@ impl From<RelayModeOption> for RelayMode {
         match value {
             RelayModeOption::Disabled => RelayMode::Disabled,
             RelayModeOption::Default => RelayMode::Default,
-            RelayModeOption::Custom(url) => RelayMode::Custom(RelayMap::from_url(url)),
+            RelayModeOption::Custom(urls) => {
+                let nodes = urls
+                    .into_iter()
+                    .flat_map(|url| RelayMap::from_url(url).nodes().cloned().collect::<Vec<_>>());
+                RelayMode::Custom(RelayMap::from_nodes(nodes).expect("relay urls are unique"))
+            }
         }
     }
 }
@@ -203,6 +463,72 @@ pub struct SendArgs {
     #[clap(long, default_value_t = AddrInfoOptions::RelayAndAddresses)]
     pub ticket_type: AddrInfoOptions,
 
+    /// Also print the ticket as a QR code.
+    #[clap(long)]
+    pub qr: bool,
+
+    /// Share the data on the running daemon, which keeps serving it after
+    /// this process exits.
+    ///
+    /// The daemon serves with its own endpoint and store, so the options for
+    /// those can not be used with this.
+    #[clap(long, overrides_with = "no_daemon", conflicts_with_all = [
+        "limit_rate", "shutdown_timeout", "store", "store_dir", "hash_cache", "relay",
+        "magic_ipv4_addr", "magic_ipv6_addr", "local_discovery", "dns_origin", "pkarr_relay",
+        "device_name", "json_progress",
+    ])]
+    pub daemon: bool,
+
+    /// Serve the data from this process, even with `daemon = true` in the config file.
+    #[clap(long, overrides_with = "daemon")]
+    pub no_daemon: bool,
+
+    /// Send the data straight to a node running `sendme receive --listen`,
+    /// instead of printing a ticket.
+    ///
+    /// Either its node id, or its device name as shown by `sendme nearby`.
+    /// Any device on the local network can claim a name, so the node id a
+    /// name belongs to is shown, and asked about if stdin is a terminal. Use
+    /// the node id to be sure where the data goes.
+    #[clap(long, conflicts_with_all = ["qr", "daemon"])]
+    pub to: Option<String>,
+
+    /// Limit the upload bandwidth, e.g. "10MB/s", shared by all receivers.
+    #[clap(long)]
+    pub limit_rate: Option<limit::Rate>,
+
+    /// On Ctrl-C, wait this long for running transfers to finish, e.g. "1m".
+    ///
+    /// No new receivers are accepted while waiting. Press Ctrl-C again to
+    /// stop right away.
+    #[clap(long, default_value = "1m")]
+    pub shutdown_timeout: humantime::Duration,
+
+    /// Where to keep the hashes of the data while sending.
+    ///
+    /// "cwd" keeps them in a directory in the current directory, and "runtime"
+    /// in $XDG_RUNTIME_DIR or the temp directory, so that read-only directories
+    /// can be sent from. "memory" keeps everything in memory, including a copy
+    /// of the data, so it refuses more than 256 MiB of data. If the current
+    /// directory is not writable, "runtime" is used instead of "cwd".
+    #[clap(long, default_value_t = StoreMode::Cwd)]
+    pub store: StoreMode,
+
+    /// Keep the hashes of the data in a new directory in this directory.
+    ///
+    /// The directory is removed again when sending ends.
+    #[clap(long, conflicts_with = "store")]
+    pub store_dir: Option<PathBuf>,
+
+    /// Remember the hashes of the files, so that sending them again does not
+    /// hash files that did not change.
+    ///
+    /// The hashes are kept in a store in the data directory, which refers to
+    /// the files instead of copying them. A file counts as changed when its
+    /// inode, size or modification time changed.
+    #[clap(long, conflicts_with_all = ["store", "store_dir"])]
+    pub hash_cache: bool,
+
     #[clap(flatten)]
     pub common: CommonArgs,
 }
@@ -210,12 +536,102 @@ pub struct SendArgs {
 #[derive(Parser, Debug)]
 pub struct ReceiveArgs {
     /// The ticket to use to connect to the sender.
-    pub ticket: BlobTicket,
+    ///
+    /// With --from, this can also be just the hash of the collection.
+    #[clap(required_unless_present_any = ["from_image", "listen"])]
+    pub ticket: Option<TicketOrHash>,
+
+    /// Also download from the sender of this ticket, can be given multiple times.
+    ///
+    /// The files are split between all senders, which must have the same
+    /// collection. If a sender goes away, the others take over its part, so
+    /// there are no retries.
+    #[clap(long, conflicts_with_all = ["from_image", "list", "retries", "retry_timeout"])]
+    pub from: Vec<BlobTicket>,
+
+    /// Read the ticket from a QR code in an image file, e.g. a screenshot.
+    #[clap(long, conflicts_with = "ticket")]
+    pub from_image: Option<PathBuf>,
+
+    /// Only list the contents of the collection, without downloading it.
+    #[clap(long)]
+    pub list: bool,
+
+    /// Download without asking for confirmation.
+    ///
+    /// Confirmation is only asked for when stdin is a terminal.
+    #[clap(short, long)]
+    pub yes: bool,
+
+    /// Wait for others to send data with `sendme send <path> --to <node-id>`.
+    ///
+    /// Data from nodes that are not allowed with --allow is only downloaded
+    /// after asking, or right away with --yes.
+    #[clap(long, conflicts_with_all = ["ticket", "from_image", "list"])]
+    pub listen: bool,
+
+    /// Accept data sent from this node id without asking, can be given multiple times.
+    #[clap(long, requires = "listen")]
+    pub allow: Vec<NodeId>,
+
+    /// Limit the download bandwidth, e.g. "10MB/s".
+    #[clap(long)]
+    pub limit_rate: Option<limit::Rate>,
+
+    #[clap(flatten)]
+    pub retry: RetryArgs,
 
     #[clap(flatten)]
     pub common: CommonArgs,
 }
 
+#[derive(Parser, Debug, Clone)]
+pub struct RetryArgs {
+    /// How often to reconnect when the connection to the sender fails.
+    ///
+    /// The data that was already downloaded is kept, so a retry only gets the rest.
+    #[clap(long, default_value_t = 5)]
+    pub retries: u32,
+
+    /// Stop retrying after this time, e.g. "2m".
+    #[clap(long, default_value = "2m")]
+    pub retry_timeout: humantime::Duration,
+}
+
+impl Default for RetryArgs {
+    /// The same as the defaults on the command line.
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            retry_timeout: Duration::from_secs(120).into(),
+        }
+    }
+}
+
+/// What to receive, a ticket or just the hash of a collection.
+#[derive(Debug, Clone)]
+pub enum TicketOrHash {
+    Ticket(BlobTicket),
+    Hash(Hash),
+}
+
+impl FromStr for TicketOrHash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ticket) = BlobTicket::from_str(s) {
+            return Ok(Self::Ticket(ticket));
+        }
+        // Hash::from_str panics on input of the wrong length
+        anyhow::ensure!(
+            s.len() == 64 || s.len() == 52,
+            "expected a ticket or the hash of a collection"
+        );
+        let hash = Hash::from_str(s).context("expected a ticket or the hash of a collection")?;
+        Ok(Self::Hash(hash))
+    }
+}
+
 /// Options to configure what is included in a [`NodeAddr`]
 #[derive(
     Copy,
@@ -243,6 +659,37 @@ pub enum AddrInfoOptions {
     Addresses,
 }
 
+/// Where a sender keeps the hashes of the data it sends.
+#[derive(
+    Copy, Clone, PartialEq, Eq, Default, Debug, derive_more::Display, derive_more::FromStr,
+)]
+pub enum StoreMode {
+    /// In a directory in the current directory.
+    #[default]
+    Cwd,
+    /// In a directory in `$XDG_RUNTIME_DIR`, or the temp directory.
+    Runtime,
+    /// In memory, along with a copy of the data.
+    ///
+    /// Only for up to [`MAX_MEMORY_STORE_SIZE`] of data.
+    Memory,
+}
+
+/// The most data that is sent with [`StoreMode::Memory`], which copies all of it into memory.
+const MAX_MEMORY_STORE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Wait for the endpoint to figure out its address, so it can be put in a ticket.
+///
+/// Without relays there is no home relay to wait for, only the direct addresses.
+async fn wait_for_address(endpoint: &Endpoint, relay: &RelayModeOption) -> anyhow::Result<()> {
+    if let RelayModeOption::Disabled = relay {
+        endpoint.direct_addresses().initialized().await?;
+    } else {
+        endpoint.home_relay().initialized().await?;
+    }
+    Ok(())
+}
+
 fn apply_options(addr: &mut NodeAddr, opts: AddrInfoOptions) {
     match opts {
         AddrInfoOptions::Id => {
@@ -264,10 +711,17 @@ fn apply_options(addr: &mut NodeAddr, opts: AddrInfoOptions) {
 /// Get the secret key or generate a new one.
 ///
 /// Print the secret key to stderr if it was generated, so the user can save it.
+/// The stored key is only used if no other sendme process uses it.
 fn get_or_create_secret(print: bool) -> anyhow::Result<SecretKey> {
     match std::env::var("IROH_SECRET") {
         Ok(secret) => SecretKey::from_str(&secret).context("invalid secret"),
         Err(_) => {
+            if let Some(key) = key::acquire()? {
+                if print {
+                    eprintln!("using stored key for node {}", key.public());
+                }
+                return Ok(key);
+            }
             let key = SecretKey::generate(rand::rngs::OsRng);
             if print {
                 eprintln!("using secret key {}", key);
@@ -282,6 +736,15 @@ fn validate_path_component(component: &str) -> anyhow::Result<()> {
         !component.contains('/'),
         "path components must not contain the only correct path separator, /"
     );
+    // anything but a plain name could end up outside of the target directory
+    let mut components = Path::new(component).components();
+    anyhow::ensure!(
+        matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ),
+        "invalid path component {component:?}"
+    );
     Ok(())
 }
 
@@ -332,6 +795,7 @@ pub fn canonicalized_path_to_string(
 
 pub async fn show_ingest_progress(
     recv: async_channel::Receiver<ImportProgress>,
+    json_progress: bool,
 ) -> anyhow::Result<()> {
     let mp = MultiProgress::new();
     mp.set_draw_target(ProgressDrawTarget::stderr());
@@ -344,6 +808,8 @@ pub async fn show_ingest_progress(
     let mut names = BTreeMap::new();
     let mut sizes = BTreeMap::new();
     let mut pbs = BTreeMap::new();
+    let mut offsets = BTreeMap::new();
+    let mut reporter = EventReporter::new(json_progress);
     loop {
         let event = recv.recv().await;
         match event {
@@ -372,12 +838,22 @@ pub async fn show_ingest_progress(
                 if let Some(pb) = pbs.get(&id) {
                     pb.set_position(offset);
                 }
+                offsets.insert(id, offset);
+                reporter.report(ChildEvent::Import {
+                    done: offsets.values().sum(),
+                    total: sizes.values().sum(),
+                });
             }
             Ok(ImportProgress::OutboardDone { id, .. }) => {
                 // you are not guaranteed to get any OutboardProgress
                 if let Some(pb) = pbs.remove(&id) {
                     pb.finish_and_clear();
                 }
+                offsets.insert(id, sizes.get(&id).copied().unwrap_or_default());
+                reporter.report_now(ChildEvent::Import {
+                    done: offsets.values().sum(),
+                    total: sizes.values().sum(),
+                });
             }
             Ok(ImportProgress::CopyProgress { .. }) => {
                 // we are not copying anything
@@ -399,52 +875,116 @@ pub async fn show_ingest_progress(
 ///
 /// If the input is a directory, the collection contains all the files in the
 /// directory.
+///
+/// With a hash cache, files that did not change since they were last imported
+/// into `db` are not hashed again.
 async fn import(
     path: PathBuf,
     db: impl iroh_blobs::store::Store,
-) -> anyhow::Result<(TempTag, u64, Collection)> {
-    let path = path.canonicalize()?;
-    anyhow::ensure!(path.exists(), "path {} does not exist", path.display());
-    let root = path.parent().context("context get parent")?;
+    json_progress: bool,
+    mut cache: Option<&mut HashCache>,
+) -> Result<(TempTag, u64, Collection), SendmeError> {
+    let path = path
+        .canonicalize()
+        .map_err(|cause| SendmeError::import(&path, cause))?;
+    let root = path
+        .parent()
+        .context("context get parent")
+        .map_err(|cause| SendmeError::import(&path, cause))?;
     // walkdir also works for files, so we don't need to special case them
     let files = WalkDir::new(path.clone()).into_iter();
-    // flatten the directory structure into a list of (name, path) pairs.
-    // ignore symlinks.
-    let data_sources: Vec<(String, PathBuf)> = files
+    // flatten the directory structure into a list of (name, path, metadata) tuples.
+    // ignore symlinks. the metadata is only needed for the cache.
+    let data_sources: Vec<(String, PathBuf, Option<Metadata>)> = files
         .map(|entry| {
-            let entry = entry?;
+            let entry = entry.map_err(|cause| {
+                let path = cause.path().unwrap_or(&path).to_path_buf();
+                SendmeError::import(&path, cause)
+            })?;
             if !entry.file_type().is_file() {
                 // Skip symlinks. Directories are handled by WalkDir.
                 return Ok(None);
             }
+            let metadata = match cache {
+                Some(_) => Some(
+                    entry
+                        .metadata()
+                        .map_err(|cause| SendmeError::import(entry.path(), cause))?,
+                ),
+                None => None,
+            };
             let path = entry.into_path();
-            let relative = path.strip_prefix(root)?;
-            let name = canonicalized_path_to_string(relative, true)?;
-            anyhow::Ok(Some((name, path)))
+            let name = path
+                .strip_prefix(root)
+                .map_err(anyhow::Error::from)
+                .and_then(|relative| canonicalized_path_to_string(relative, true))
+                .map_err(|cause| SendmeError::import(&path, cause))?;
+            Ok(Some((name, path, metadata)))
         })
         .filter_map(Result::transpose)
-        .collect::<anyhow::Result<Vec<_>>>()?;
+        .collect::<Result<Vec<_>, SendmeError>>()?;
     let (send, recv) = async_channel::bounded(32);
     let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
-    let show_progress = tokio::spawn(show_ingest_progress(recv));
+    let show_progress = tokio::spawn(show_ingest_progress(recv, json_progress));
     // import all the files, using num_cpus workers, return names and temp tags
-    let mut names_and_tags = futures_lite::stream::iter(data_sources)
-        .map(|(name, path)| {
+    let cache_ref = cache.as_deref();
+    let imported = futures_lite::stream::iter(data_sources)
+        .map(|(name, path, metadata)| {
             let db = db.clone();
             let progress = progress.clone();
+            let cached = cache_ref
+                .zip(metadata.as_ref())
+                .and_then(|(cache, metadata)| cache.get(&path, metadata));
             async move {
+                if let Some(hash) = cached {
+                    let status = db
+                        .entry_status(&hash)
+                        .await
+                        .map_err(|cause| SendmeError::import(&path, cause))?;
+                    if matches!(status, EntryStatus::Complete) {
+                        let temp_tag = db.temp_tag(HashAndFormat::raw(hash));
+                        let file_size = metadata.as_ref().map_or(0, Metadata::len);
+                        return Ok((name, temp_tag, file_size, None));
+                    }
+                }
                 let (temp_tag, file_size) = db
-                    .import_file(path, ImportMode::TryReference, BlobFormat::Raw, progress)
-                    .await?;
-                anyhow::Ok((name, temp_tag, file_size))
+                    .import_file(
+                        path.clone(),
+                        ImportMode::TryReference,
+                        BlobFormat::Raw,
+                        progress,
+                    )
+                    .await
+                    .map_err(|cause| SendmeError::import(&path, cause))?;
+                // remember the file as it was before hashing, a change while hashing
+                // makes it be hashed again next time
+                let hashed = metadata.map(|metadata| (path, metadata, *temp_tag.hash()));
+                Ok((name, temp_tag, file_size, hashed))
             }
         })
         .buffered_unordered(num_cpus::get())
         .collect::<Vec<_>>()
         .await
         .into_iter()
-        .collect::<anyhow::Result<Vec<_>>>()?;
+        .collect::<Result<Vec<_>, SendmeError>>()?;
     drop(progress);
+    let total = imported.len();
+    let mut unchanged = 0;
+    let mut names_and_tags = Vec::with_capacity(total);
+    for (name, temp_tag, file_size, hashed) in imported {
+        match (hashed, cache.as_deref_mut()) {
+            (Some((path, metadata, hash)), Some(cache)) => cache.insert(path, &metadata, hash),
+            (None, Some(_)) => unchanged += 1,
+            _ => {}
+        }
+        names_and_tags.push((name, temp_tag, file_size));
+    }
+    if unchanged > 0 && !json_progress {
+        eprintln!(
+            "{unchanged} of {total} files are unchanged since they were last sent, \
+             not hashing them again"
+        );
+    }
     names_and_tags.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
     // total size of all files
     let size = names_and_tags.iter().map(|(_, _, size)| *size).sum::<u64>();
@@ -454,14 +994,28 @@ async fn import(
         .into_iter()
         .map(|(name, tag, _)| ((name, *tag.hash()), tag))
         .unzip::<_, _, Collection, Vec<_>>();
-    let temp_tag = collection.clone().store(&db).await?;
+    let temp_tag = collection
+        .clone()
+        .store(&db)
+        .await
+        .map_err(|cause| SendmeError::import(&path, cause))?;
     // now that the collection is stored, we can drop the tags
     // data is protected by the collection
     drop(tags);
-    show_progress.await??;
+    show_progress
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|res| res)
+        .map_err(|cause| SendmeError::import(&path, cause))?;
     Ok((temp_tag, size, collection))
 }
 
+/// The name of the top level file or directory of a collection.
+fn collection_name(collection: &Collection) -> Option<String> {
+    let (name, _) = collection.iter().next()?;
+    name.split('/').next().map(ToOwned::to_owned)
+}
+
 fn get_export_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
     let parts = name.split('/');
     let mut path = root.to_path_buf();
@@ -472,25 +1026,34 @@ fn get_export_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
     Ok(path)
 }
 
-async fn export(db: impl iroh_blobs::store::Store, collection: Collection) -> anyhow::Result<()> {
-    let root = std::env::current_dir()?;
+async fn export(
+    db: impl iroh_blobs::store::Store,
+    collection: Collection,
+) -> Result<(), SendmeError> {
+    let root =
+        std::env::current_dir().map_err(|cause| SendmeError::export(Path::new("."), cause))?;
     for (name, hash) in collection.iter() {
-        let target = get_export_path(&root, name)?;
+        let target = get_export_path(&root, name)
+            .map_err(|cause| SendmeError::export(Path::new(name), cause))?;
         if target.exists() {
             eprintln!(
                 "target {} already exists. Export stopped.",
                 target.display()
             );
             eprintln!("You can remove the file or directory and try again. The download will not be repeated.");
-            anyhow::bail!("target {} already exists", target.display());
+            return Err(SendmeError::export(
+                &target,
+                io::Error::new(io::ErrorKind::AlreadyExists, "the target already exists"),
+            ));
         }
         db.export(
             *hash,
-            target,
+            target.clone(),
             ExportMode::TryReference,
             Box::new(move |_position| Ok(())),
         )
-        .await?;
+        .await
+        .map_err(|cause| SendmeError::export(&target, cause))?;
     }
     Ok(())
 }
@@ -519,6 +1082,9 @@ impl SendStatus {
         current.set_message("waiting for requests");
         ClientStatus {
             current: current.into(),
+            connections: Default::default(),
+            transfers: Arc::new(tokio::sync::watch::Sender::new(0)),
+            receivers: Arc::new(tokio::sync::watch::Sender::new(0)),
         }
     }
 }
@@ -526,6 +1092,98 @@ impl SendStatus {
 #[derive(Debug, Clone)]
 struct ClientStatus {
     current: Arc<ProgressBar>,
+    /// transfer statistics for each open connection, by connection id
+    connections: Arc<Mutex<BTreeMap<u64, ConnectionStats>>>,
+    /// the number of requests that are being answered
+    transfers: Arc<tokio::sync::watch::Sender<u64>>,
+    /// the number of open connections that requested something
+    ///
+    /// A request is done once its data is sent, which is before the receiver
+    /// has it, so a transfer only ends when the receiver closes the connection.
+    receivers: Arc<tokio::sync::watch::Sender<u64>>,
+}
+
+/// Statistics about the transfers on a single connection.
+#[derive(Debug, Default)]
+struct ConnectionStats {
+    /// bytes sent over the connection
+    sent: u64,
+    /// number of completed transfers
+    completed: u64,
+    /// number of aborted transfers
+    aborted: u64,
+    /// number of requests that are being answered
+    running: u64,
+    /// whether anything was requested, so the connection counts as a receiver
+    requested: bool,
+}
+
+impl ClientStatus {
+    fn update_connection<R>(
+        &self,
+        connection_id: u64,
+        f: impl FnOnce(&mut ConnectionStats) -> R,
+    ) -> R {
+        f(self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(connection_id)
+            .or_default())
+    }
+
+    /// Wait until the requests of a closed connection have ended, or `timeout` has passed.
+    ///
+    /// The provider answers each request in a task of its own, which may still
+    /// be finishing when the connection is gone.
+    async fn wait_for_connection(&self, connection_id: u64, timeout: Duration) {
+        let mut transfers = self.transfers.subscribe();
+        let running = || self.update_connection(connection_id, |c| c.running);
+        tokio::time::timeout(timeout, async {
+            while running() > 0 && transfers.changed().await.is_ok() {}
+        })
+        .await
+        .ok();
+    }
+
+    /// Wait until no receivers are connected, or `timeout` has passed, showing a countdown.
+    async fn wait_for_transfers(&self, mp: &MultiProgress, timeout: Duration) {
+        let mut transfers = self.receivers.subscribe();
+        let running = *transfers.borrow_and_update();
+        if running == 0 {
+            return;
+        }
+        eprintln!("waiting for {running} transfers to finish, press Ctrl-C again to stop now");
+        let deadline = tokio::time::Instant::now() + timeout;
+        let countdown = mp.add(ProgressBar::hidden());
+        countdown.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.yellow} {msg}")
+                .unwrap(),
+        );
+        countdown.enable_steady_tick(Duration::from_millis(100));
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            let running = *transfers.borrow_and_update();
+            if running == 0 {
+                break;
+            }
+            let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if left.is_zero() {
+                eprintln!("stopping with {running} transfers still running");
+                break;
+            }
+            countdown.set_message(format!(
+                "waiting for {running} transfers to finish, stopping in {}s",
+                left.as_millis().div_ceil(1000)
+            ));
+            tokio::select! {
+                _ = tick.tick() => {}
+                _ = transfers.changed() => {}
+            }
+        }
+        countdown.finish_and_clear();
+    }
 }
 
 impl Drop for ClientStatus {
@@ -544,6 +1202,33 @@ impl CustomEventSender for ClientStatus {
 
     fn try_send(&self, event: provider::Event) {
         tracing::info!("{:?}", event);
+        // the provider does not always end a request with an event, so the
+        // requests that are left when the connection closes are counted then
+        match event {
+            provider::Event::GetRequestReceived { connection_id, .. } => {
+                let first = self.update_connection(connection_id, |c| {
+                    c.running += 1;
+                    !std::mem::replace(&mut c.requested, true)
+                });
+                self.transfers.send_modify(|n| *n += 1);
+                if first {
+                    self.receivers.send_modify(|n| *n += 1);
+                }
+            }
+            provider::Event::TransferCompleted { connection_id, .. }
+            | provider::Event::TransferAborted { connection_id, .. } => {
+                // a request that failed before it was read was never counted
+                let counted = self.update_connection(connection_id, |c| {
+                    let counted = c.running > 0;
+                    c.running = c.running.saturating_sub(1);
+                    counted
+                });
+                if counted {
+                    self.transfers.send_modify(|n| *n = n.saturating_sub(1));
+                }
+            }
+            _ => {}
+        }
         let msg = match event {
             provider::Event::ClientConnected { connection_id } => {
                 Some(format!("{} got connection", connection_id))
@@ -565,13 +1250,27 @@ impl CustomEventSender for ClientStatus {
                 connection_id,
                 stats,
                 ..
-            } => Some(format!(
-                "{} transfer completed {} {}",
+            } => {
+                self.update_connection(connection_id, |c| {
+                    c.completed += 1;
+                    c.sent += stats.send.write_bytes.size;
+                });
+                Some(format!(
+                    "{} transfer completed {} {}",
+                    connection_id,
+                    stats.send.write_bytes.size,
+                    HumanDuration(stats.send.write_bytes.stats.duration)
+                ))
+            }
+            provider::Event::TransferAborted {
                 connection_id,
-                stats.send.write_bytes.size,
-                HumanDuration(stats.send.write_bytes.stats.duration)
-            )),
-            provider::Event::TransferAborted { connection_id, .. } => {
+                stats,
+                ..
+            } => {
+                self.update_connection(connection_id, |c| {
+                    c.aborted += 1;
+                    c.sent += stats.map(|s| s.send.write_bytes.size).unwrap_or_default();
+                });
                 Some(format!("{} transfer completed", connection_id))
             }
             _ => None,
@@ -582,16 +1281,148 @@ impl CustomEventSender for ClientStatus {
     }
 }
 
+/// How long to wait for the requests of a closed connection to end.
+const REQUEST_END_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the blobs protocol, and records a history entry for every connection.
+#[derive(Debug, Clone)]
+struct SendProtocol<S> {
+    blobs: Blobs<S>,
+    status: ClientStatus,
+    /// template for the history entries, filled in once the data is imported
+    share: Arc<Mutex<Option<history::Entry>>>,
+    /// set when shutting down, so that no new receivers are accepted
+    closing: Arc<AtomicBool>,
+    /// the bandwidth limit, shared by all receivers
+    limit: Arc<limit::RateLimit>,
+}
+
+impl<S> SendProtocol<S> {
+    fn new(blobs: Blobs<S>, status: ClientStatus, limit: Arc<limit::RateLimit>) -> Self {
+        Self {
+            blobs,
+            status,
+            share: Default::default(),
+            closing: Default::default(),
+            limit,
+        }
+    }
+}
+
+impl<S: iroh_blobs::store::Store> ProtocolHandler for SendProtocol<S> {
+    fn accept(&self, conn: Connecting) -> Boxed<anyhow::Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let conn = conn.await?;
+            if this.closing.load(Ordering::Relaxed) {
+                conn.close(1u32.into(), b"shutting down");
+                return Ok(());
+            }
+            let peer = conn.remote_node_id()?;
+            // the provider uses the stable id as the connection id in its events
+            let connection_id = conn.stable_id() as u64;
+            provider::handle_connection(
+                conn,
+                this.limit.limit_send(this.blobs.store().clone()),
+                this.blobs.events().clone(),
+                this.blobs.rt().clone(),
+            )
+            .await;
+            this.status
+                .wait_for_connection(connection_id, REQUEST_END_TIMEOUT)
+                .await;
+            let stats = this
+                .status
+                .connections
+                .lock()
+                .unwrap()
+                .remove(&connection_id)
+                .unwrap_or_default();
+            if stats.running > 0 {
+                this.status
+                    .transfers
+                    .send_modify(|n| *n = n.saturating_sub(stats.running));
+            }
+            if stats.requested {
+                this.status
+                    .receivers
+                    .send_modify(|n| *n = n.saturating_sub(1));
+            }
+            if stats.completed + stats.aborted == 0 {
+                // the peer did not request anything
+                return Ok(());
+            }
+            if let Some(mut entry) = this.share.lock().unwrap().clone() {
+                entry.time = chrono::Utc::now();
+                entry.size = stats.sent;
+                entry.peer = Some(peer);
+                entry.outcome = if stats.aborted == 0 {
+                    history::Outcome::Completed
+                } else {
+                    history::Outcome::Aborted
+                };
+                history::record(entry);
+            }
+            Ok(())
+        })
+    }
+
+    fn shutdown(&self) -> Boxed<()> {
+        self.blobs.shutdown()
+    }
+}
+
 async fn send(args: SendArgs) -> anyhow::Result<()> {
+    let limit = limit::RateLimit::new(args.limit_rate);
+    // the GUI changes the limit while the transfer runs
+    if args.common.json_progress {
+        limit.follow_commands();
+    }
+    if let Some(to) = args.to {
+        let addr = match to.parse::<NodeId>() {
+            Ok(node_id) => NodeAddr::new(node_id),
+            // not a node id, so look for a device with that name
+            Err(_) => {
+                let addr = mdns::find_listener(&to).await?;
+                if !confirm_recipient(&to, &addr.node_id).await? {
+                    return Err(exit::with_code(
+                        exit::ABORTED,
+                        anyhow::anyhow!("send cancelled"),
+                    ));
+                }
+                addr
+            }
+        };
+        return inbox::push(addr, args.path, args.common, limit).await;
+    }
+    if args.daemon && !args.no_daemon {
+        return send_with_daemon(daemon::connect().await?, args).await;
+    }
+    if args.store == StoreMode::Memory {
+        let size = WalkDir::new(&args.path)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum::<u64>();
+        anyhow::ensure!(
+            size <= MAX_MEMORY_STORE_SIZE,
+            "{} is {}, --store memory only takes up to {}, use --store runtime instead",
+            args.path.display(),
+            HumanBytes(size),
+            HumanBytes(MAX_MEMORY_STORE_SIZE)
+        );
+    }
     let secret_key = get_or_create_secret(args.common.verbose > 0)?;
+    let relay = args.common.relay_mode()?;
     // create a magicsocket endpoint
     let mut builder = Endpoint::builder()
         .alpns(vec![iroh_blobs::protocol::ALPN.to_vec()])
         .secret_key(secret_key)
-        .relay_mode(args.common.relay.into());
+        .relay_mode(relay.clone().into());
     if args.ticket_type == AddrInfoOptions::Id {
-        builder =
-            builder.add_discovery(|secret_key| Some(PkarrPublisher::n0_dns(secret_key.clone())));
+        builder = builder.add_discovery(args.common.pkarr_publisher());
     }
     if let Some(addr) = args.common.magic_ipv4_addr {
         builder = builder.bind_addr_v4(addr);
@@ -599,39 +1430,135 @@ async fn send(args: SendArgs) -> anyhow::Result<()> {
     if let Some(addr) = args.common.magic_ipv6_addr {
         builder = builder.bind_addr_v6(addr);
     }
+    if args.common.local_discovery {
+        let device = args.common.device(false);
+        builder =
+            builder.add_discovery(move |secret_key| mdns::discovery(secret_key.public(), device));
+    }
 
-    // use a flat store - todo: use a partial in mem store instead
-    let suffix = rand::thread_rng().gen::<[u8; 16]>();
-    let cwd = std::env::current_dir()?;
-    let blobs_data_dir = cwd.join(format!(".sendme-send-{}", HEXLOWER.encode(&suffix)));
-    if blobs_data_dir.exists() {
-        println!(
-            "can not share twice from the same directory: {}",
-            cwd.display(),
-        );
-        std::process::exit(1);
+    gc::check(None, args.store_dir.as_deref(), true).await;
+    let endpoint = builder.bind().await?;
+    let ps = SendStatus::new();
+    let status = ps.new_client();
+    if args.store == StoreMode::Memory {
+        let blobs = Blobs::memory()
+            .events(status.clone().into())
+            .build(&endpoint);
+        let protocol = SendProtocol::new(blobs, status, limit);
+        return serve(args, relay, endpoint, protocol, ps, None).await;
+    }
+    if args.hash_cache {
+        match HashCache::open() {
+            Ok(cache) => {
+                let blobs = Blobs::persistent(cache.store_dir())
+                    .await?
+                    .events(status.clone().into())
+                    .build(&endpoint);
+                let protocol = SendProtocol::new(blobs, status, limit);
+                return serve(args, relay, endpoint, protocol, ps, Some(cache)).await;
+            }
+            Err(cause) => eprintln!("can not use the hash cache ({cause:#}), hashing all files"),
+        }
+    }
+    let (blobs_data_dir, lock) = create_send_store(args.store, args.store_dir.as_deref())?;
+    let res = async {
+        let blobs = Blobs::persistent(&blobs_data_dir)
+            .await?
+            .events(status.clone().into())
+            .build(&endpoint);
+        let protocol = SendProtocol::new(blobs, status, limit);
+        serve(args, relay, endpoint, protocol, ps, None).await
     }
+    .await;
+    drop(lock);
+    tokio::fs::remove_dir_all(blobs_data_dir).await?;
+    res
+}
 
-    tokio::fs::create_dir_all(&blobs_data_dir).await?;
+/// The directory for files that only live as long as the process, `$XDG_RUNTIME_DIR` if set.
+fn runtime_dir() -> PathBuf {
+    dirs::runtime_dir().unwrap_or_else(std::env::temp_dir)
+}
 
-    let endpoint = builder.bind().await?;
-    let ps = SendStatus::new();
-    let blobs = Blobs::persistent(&blobs_data_dir)
-        .await?
-        .events(ps.new_client().into())
-        .build(&endpoint);
+/// Create and lock a new store directory to send from, in `parent` or where `mode` asks for it.
+///
+/// Every send gets its own directory, so several can run in the same directory.
+/// If the current directory is not writable, the runtime directory is used instead.
+fn create_send_store(
+    mode: StoreMode,
+    parent: Option<&Path>,
+) -> anyhow::Result<(PathBuf, gc::StoreLock)> {
+    let suffix = rand::thread_rng().gen::<[u8; 16]>();
+    let name = format!(".sendme-send-{}", HEXLOWER.encode(&suffix));
+    if let Some(parent) = parent {
+        let blobs_data_dir = parent.join(name);
+        let lock = gc::lock(&blobs_data_dir)
+            .with_context(|| format!("can not create a store in {}", parent.display()))?;
+        return Ok((blobs_data_dir, lock));
+    }
+    if mode == StoreMode::Cwd {
+        let cwd = std::env::current_dir()?;
+        let blobs_data_dir = cwd.join(&name);
+        match gc::lock(&blobs_data_dir) {
+            Ok(lock) => return Ok((blobs_data_dir, lock)),
+            Err(cause) => eprintln!(
+                "can not create a store in {} ({cause:#}), using {} instead",
+                cwd.display(),
+                runtime_dir().display()
+            ),
+        }
+    }
+    let blobs_data_dir = runtime_dir().join(name);
+    let lock = gc::lock(&blobs_data_dir)?;
+    Ok((blobs_data_dir, lock))
+}
+
+/// Import the data to send into the store of `protocol`, and serve it until Ctrl-C.
+///
+/// The hash cache, if any, must belong to the store of `protocol`.
+async fn serve<S: iroh_blobs::store::Store>(
+    args: SendArgs,
+    relay: RelayModeOption,
+    endpoint: Endpoint,
+    protocol: SendProtocol<S>,
+    ps: SendStatus,
+    mut cache: Option<HashCache>,
+) -> anyhow::Result<()> {
+    let blobs = protocol.blobs.clone();
 
     let router = iroh::protocol::Router::builder(endpoint)
-        .accept(iroh_blobs::ALPN, blobs.clone())
+        .accept(iroh_blobs::ALPN, protocol.clone())
         .spawn()
         .await?;
 
     let path = args.path;
-    let (temp_tag, size, collection) = import(path.clone(), blobs.store().clone()).await?;
+    if let Some(cache) = &mut cache {
+        if let Err(cause) = cache.prune(blobs.store()).await {
+            tracing::warn!("failed to clean up the hash cache: {cause:#}");
+        }
+    }
+    let (temp_tag, size, collection) = import(
+        path.clone(),
+        blobs.store().clone(),
+        args.common.json_progress,
+        cache.as_mut(),
+    )
+    .await?;
+    if let Some(cache) = &cache {
+        // only remember the hashes once the store has them on disk
+        let res = blobs.store().sync().await;
+        if let Err(cause) = res.map_err(anyhow::Error::from).and_then(|()| cache.save()) {
+            tracing::warn!("failed to save the hash cache: {cause:#}");
+        }
+    }
     let hash = *temp_tag.hash();
+    let mut share = history::Entry::new(history::Direction::Send, hash);
+    share.name = collection_name(&collection).unwrap_or(share.name);
+    share.path = path.canonicalize().ok();
+    *protocol.share.lock().unwrap() = Some(share);
 
     // wait for the endpoint to figure out its address before making a ticket
-    let _ = router.endpoint().home_relay().initialized().await?;
+    wait_for_address(router.endpoint(), &relay).await?;
 
     // make a ticket
     let mut addr = router.endpoint().node_addr().await?;
@@ -652,15 +1579,30 @@ async fn send(args: SendArgs) -> anyhow::Result<()> {
     }
     println!("to get this data, use");
     println!("sendme receive {}", ticket);
+    if args.qr {
+        println!("{}", qr::render_unicode(&ticket.to_string())?);
+    }
 
     drop(temp_tag);
 
     // Wait for exit
     tokio::signal::ctrl_c().await?;
 
+    // let the receivers that are downloading finish, unless asked again
+    protocol.closing.store(true, Ordering::Relaxed);
+    tokio::select! {
+        _ = protocol.status.wait_for_transfers(&ps.mp, *args.shutdown_timeout) => {}
+        res = tokio::signal::ctrl_c() => res?,
+    }
+
     println!("shutting down");
-    tokio::time::timeout(Duration::from_secs(2), router.shutdown()).await??;
-    tokio::fs::remove_dir_all(blobs_data_dir).await?;
+    // a forced stop must still clean up the store
+    if tokio::time::timeout(Duration::from_secs(2), router.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!("shutting down the router timed out");
+    }
 
     Ok(())
 }
@@ -681,6 +1623,7 @@ fn make_download_progress() -> ProgressBar {
 pub async fn show_download_progress(
     recv: async_channel::Receiver<DownloadProgress>,
     total_size: u64,
+    json_progress: bool,
 ) -> anyhow::Result<()> {
     let mp = MultiProgress::new();
     mp.set_draw_target(ProgressDrawTarget::stderr());
@@ -688,10 +1631,18 @@ pub async fn show_download_progress(
     op.set_message(format!("{} Connecting ...\n", style("[1/3]").bold().dim()));
     let mut total_done = 0;
     let mut sizes = BTreeMap::new();
+    // blobs are downloaded in parallel when there are several senders
+    let mut current = BTreeMap::new();
+    // several senders may work on the same piece, only the first to finish counts
+    let mut finished = BTreeSet::new();
+    let mut reporter = EventReporter::new(json_progress);
     loop {
         let x = recv.recv().await;
         match x {
             Ok(DownloadProgress::Connected) => {
+                // after a retry, the blobs that were in progress start over
+                current.clear();
+                finished.clear();
                 op.set_message(format!("{} Requesting ...\n", style("[2/3]").bold().dim()));
             }
             Ok(DownloadProgress::FoundHashSeq { children, .. }) => {
@@ -703,17 +1654,34 @@ pub async fn show_download_progress(
                 op.set_length(total_size);
                 op.reset();
             }
+            Ok(
+                DownloadProgress::Found { id, .. }
+                | DownloadProgress::Progress { id, .. }
+                | DownloadProgress::Done { id },
+            ) if finished.contains(&id) => {}
             Ok(DownloadProgress::Found { id, size, .. }) => {
                 sizes.insert(id, size);
             }
-            Ok(DownloadProgress::Progress { offset, .. }) => {
-                op.set_position(total_done + offset);
+            Ok(DownloadProgress::Progress { id, offset }) => {
+                current.insert(id, offset);
+                let done = total_done + current.values().sum::<u64>();
+                op.set_position(done);
+                reporter.report(ChildEvent::Download {
+                    done,
+                    total: total_size,
+                });
             }
             Ok(DownloadProgress::Done { id }) => {
+                finished.insert(id);
+                current.remove(&id);
                 total_done += sizes.remove(&id).unwrap_or_default();
             }
             Ok(DownloadProgress::AllDone(stats)) => {
                 op.finish_and_clear();
+                reporter.report_now(ChildEvent::Download {
+                    done: total_size,
+                    total: total_size,
+                });
                 eprintln!(
                     "Transferred {} in {}, {}/s",
                     HumanBytes(stats.bytes_read),
@@ -734,117 +1702,562 @@ pub async fn show_download_progress(
     Ok(())
 }
 
-fn show_get_error(e: anyhow::Error) -> anyhow::Error {
-    if let Some(err) = e.downcast_ref::<DecodeError>() {
-        match err {
-            DecodeError::NotFound => {
-                eprintln!("{}", style("send side no longer has a file").yellow())
+/// The delay before the first retry, which doubles with every further retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay between two retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Why a download gave up on its sender.
+#[derive(Debug)]
+enum SenderFailure {
+    /// The sender closed the connection or no longer has the data.
+    Gone(anyhow::Error),
+    /// The sender could not be reached within the retries.
+    Unreachable(anyhow::Error),
+}
+
+impl Display for SenderFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SenderFailure::Gone(_) => write!(f, "the sender is gone"),
+            SenderFailure::Unreachable(_) => write!(f, "the sender can not be reached"),
+        }
+    }
+}
+
+impl std::error::Error for SenderFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SenderFailure::Gone(cause) | SenderFailure::Unreachable(cause) => Some(cause.as_ref()),
+        }
+    }
+}
+
+/// How to go on after a failed attempt to get data from a sender.
+enum Failure {
+    /// Trying again might work.
+    Transient,
+    /// The sender is gone.
+    Gone,
+    /// Something is wrong that trying again will not fix.
+    Fatal,
+}
+
+impl Failure {
+    fn of(cause: &anyhow::Error) -> Self {
+        for cause in cause.chain() {
+            if let Some(e) = cause.downcast_ref::<GetError>() {
+                match e {
+                    GetError::NotFound(_) => return Failure::Gone,
+                    GetError::NoncompliantNode(_)
+                    | GetError::BadRequest(_)
+                    | GetError::LocalFailure(_) => return Failure::Fatal,
+                    // the cause tells whether the sender closed the connection
+                    GetError::RemoteReset(_) | GetError::Io(_) => {}
+                }
             }
-            DecodeError::LeafNotFound(_) | DecodeError::ParentNotFound(_) => eprintln!(
-                "{}",
-                style("send side no longer has part of a file").yellow()
-            ),
-            DecodeError::Io(err) => eprintln!(
-                "{}",
-                style(format!("generic network error: {}", err)).yellow()
-            ),
-            DecodeError::Read(err) => eprintln!(
-                "{}",
-                style(format!("error reading data from quinn: {}", err)).yellow()
-            ),
-            DecodeError::LeafHashMismatch(_) | DecodeError::ParentHashMismatch(_) => {
-                eprintln!("{}", style("send side sent wrong data").red())
+            if let Some(e) = cause.downcast_ref::<DecodeError>() {
+                match e {
+                    DecodeError::NotFound
+                    | DecodeError::ParentNotFound(_)
+                    | DecodeError::LeafNotFound(_) => return Failure::Gone,
+                    DecodeError::ParentHashMismatch(_) | DecodeError::LeafHashMismatch(_) => {
+                        return Failure::Fatal
+                    }
+                    DecodeError::Read(_) | DecodeError::Io(_) => {}
+                }
             }
-        };
-    } else if let Some(header_error) = e.downcast_ref::<AtBlobHeaderNextError>() {
-        // TODO(iroh-bytes): get_to_db should have a concrete error type so you don't have to guess
-        match header_error {
-            AtBlobHeaderNextError::Io(err) => eprintln!(
-                "{}",
-                style(format!("generic network error: {}", err)).yellow()
-            ),
-            AtBlobHeaderNextError::Read(err) => eprintln!(
-                "{}",
-                style(format!("error reading data from quinn: {}", err)).yellow()
-            ),
-            AtBlobHeaderNextError::NotFound => {
-                eprintln!("{}", style("send side no longer has a file").yellow())
+            if let Some(AtBlobHeaderNextError::NotFound) = cause.downcast_ref() {
+                return Failure::Gone;
             }
-        };
-    } else {
-        eprintln!(
-            "{}",
-            style(format!("generic error: {:?}", e.root_cause())).red()
-        );
+            if let Some(ConnectionError::ApplicationClosed(_)) = cause.downcast_ref() {
+                return Failure::Gone;
+            }
+        }
+        Failure::Transient
+    }
+}
+
+/// Keeps track of the attempts to get data from a sender.
+struct Retries {
+    left: u32,
+    delay: Duration,
+    deadline: tokio::time::Instant,
+}
+
+impl Retries {
+    fn new(args: &RetryArgs) -> Self {
+        Self {
+            left: args.retries,
+            delay: RETRY_DELAY,
+            deadline: tokio::time::Instant::now() + *args.retry_timeout,
+        }
+    }
+
+    /// Wait before the next attempt, or fail if there should be none.
+    async fn wait(&mut self, cause: anyhow::Error) -> anyhow::Result<()> {
+        match Failure::of(&cause) {
+            Failure::Transient => {}
+            Failure::Gone => return Err(SenderFailure::Gone(cause).into()),
+            Failure::Fatal => return Err(cause),
+        }
+        if self.left == 0 || tokio::time::Instant::now() + self.delay > self.deadline {
+            return Err(SenderFailure::Unreachable(cause).into());
+        }
+        eprintln!("{cause:#}, retrying in {}", HumanDuration(self.delay));
+        tokio::time::sleep(self.delay).await;
+        self.left -= 1;
+        self.delay = (self.delay * 2).min(MAX_RETRY_DELAY);
+        Ok(())
     }
-    e
 }
 
 async fn receive(args: ReceiveArgs) -> anyhow::Result<()> {
-    let ticket = args.ticket;
-    let addr = ticket.node_addr().clone();
-    let secret_key = get_or_create_secret(args.common.verbose > 0)?;
+    let limit = limit::RateLimit::new(args.limit_rate);
+    // the GUI changes the limit while the transfer runs
+    if args.common.json_progress {
+        limit.follow_commands();
+    }
+    if args.listen {
+        let policy = inbox::Policy::Listen {
+            allow: args.allow.into_iter().collect(),
+            yes: args.yes,
+        };
+        return inbox::run(args.common, policy, limit).await;
+    }
+    let ticket = match (&args.from_image, args.ticket) {
+        (Some(path), _) => qr::decode_ticket_from_image(path)
+            .map_err(|cause| exit::with_code(exit::BAD_TICKET, cause))?,
+        (None, Some(TicketOrHash::Ticket(ticket))) => ticket,
+        (None, Some(TicketOrHash::Hash(hash))) => {
+            return receive_from(hash, args.from, &args.common, args.yes, &limit).await;
+        }
+        (None, None) => anyhow::bail!("missing ticket"),
+    };
+    if !args.from.is_empty() {
+        let mut tickets = vec![ticket.clone()];
+        tickets.extend(args.from);
+        return receive_from(ticket.hash(), tickets, &args.common, args.yes, &limit).await;
+    }
+    if args.list {
+        return list_ticket(ticket, &args.common).await;
+    }
+    // a partial download of the same data is resumed
+    let resume = std::env::current_dir()?.join(format!(".sendme-get-{}", ticket.hash().to_hex()));
+    gc::check(Some(&resume), None, !args.yes).await;
+    let mut entry = history::Entry::for_ticket(&ticket);
+    let res = receive_ticket(
+        ticket,
+        &args.common,
+        args.yes,
+        &limit,
+        &args.retry,
+        &mut entry,
+    )
+    .await;
+    if let Err(e) = &res {
+        entry.outcome = history::Outcome::Failed(format!("{e:#}"));
+    }
+    history::record(entry);
+    res
+}
+
+/// Download a collection from the senders of several tickets at once, recording it in the history.
+async fn receive_from(
+    hash: Hash,
+    tickets: Vec<BlobTicket>,
+    common: &CommonArgs,
+    yes: bool,
+    limit: &Arc<limit::RateLimit>,
+) -> anyhow::Result<()> {
+    let Some(first) = tickets.first() else {
+        anyhow::bail!("a hash needs at least one --from ticket to download from");
+    };
+    for ticket in &tickets {
+        if ticket.hash() != hash || ticket.format() != BlobFormat::HashSeq {
+            return Err(exit::with_code(
+                exit::BAD_TICKET,
+                anyhow::anyhow!(
+                    "the ticket of {} is not for collection {}",
+                    ticket.node_addr().node_id,
+                    print_hash(&hash, common.format)
+                ),
+            ));
+        }
+    }
+    let resume = std::env::current_dir()?.join(format!(".sendme-get-{}", hash.to_hex()));
+    gc::check(Some(&resume), None, !yes).await;
+    let mut entry = history::Entry::for_ticket(first);
+    let endpoint = receive_endpoint(first, common, Some(limit)).await?;
+    let res = multi::download(&endpoint, hash, &tickets, common, yes, limit, &mut entry).await;
+    endpoint.close().await;
+    if let Err(e) = &res {
+        entry.outcome = history::Outcome::Failed(format!("{e:#}"));
+    }
+    history::record(entry);
+    res
+}
+
+/// Create an endpoint to connect to the sender of a ticket.
+///
+/// Downloads that are limited need the limit in the transport config from the start.
+async fn receive_endpoint(
+    ticket: &BlobTicket,
+    common: &CommonArgs,
+    limit: Option<&Arc<limit::RateLimit>>,
+) -> anyhow::Result<Endpoint> {
+    let secret_key = get_or_create_secret(common.verbose > 0)?;
     let mut builder = Endpoint::builder()
         .alpns(vec![])
         .secret_key(secret_key)
-        .relay_mode(args.common.relay.into());
+        .relay_mode(common.relay_mode()?.into());
+    if let Some(limit) = limit {
+        builder = builder.transport_config(limit.transport_config());
+    }
 
     if ticket.node_addr().relay_url.is_none() && ticket.node_addr().direct_addresses.is_empty() {
-        builder = builder.add_discovery(|_| Some(DnsDiscovery::n0_dns()));
+        let dns = common.dns_discovery();
+        if common.local_discovery {
+            // a failing DNS lookup must not stop the local discovery
+            builder = builder.add_discovery(|_| Some(mdns::IgnoreErrors(dns)));
+        } else {
+            builder = builder.add_discovery(|_| Some(dns));
+        }
     }
-    if let Some(addr) = args.common.magic_ipv4_addr {
+    if let Some(addr) = common.magic_ipv4_addr {
         builder = builder.bind_addr_v4(addr);
     }
-    if let Some(addr) = args.common.magic_ipv6_addr {
+    if let Some(addr) = common.magic_ipv6_addr {
         builder = builder.bind_addr_v6(addr);
     }
-    let endpoint = builder.bind().await?;
-    let dir_name = format!(".sendme-get-{}", ticket.hash().to_hex());
-    let iroh_data_dir = std::env::current_dir()?.join(dir_name);
-    let db = iroh_blobs::store::fs::Store::load(&iroh_data_dir).await?;
-    let mp = MultiProgress::new();
-    let connect_progress = mp.add(ProgressBar::hidden());
-    connect_progress.set_draw_target(ProgressDrawTarget::stderr());
-    connect_progress.set_style(ProgressStyle::default_spinner());
-    connect_progress.set_message(format!("connecting to {}", addr.node_id));
-    let connection = endpoint.connect(addr, iroh_blobs::protocol::ALPN).await?;
-    let hash_and_format = HashAndFormat {
-        hash: ticket.hash(),
-        format: ticket.format(),
+    if common.local_discovery {
+        let device = common.device(false);
+        builder =
+            builder.add_discovery(move |secret_key| mdns::discovery(secret_key.public(), device));
+    }
+    builder.bind().await
+}
+
+/// Get a collection and the sizes of all its blobs, without downloading any file data.
+///
+/// The first size is the one of the collection metadata blob.
+async fn get_collection_and_sizes(
+    connection: &Connection,
+    hash: &Hash,
+) -> Result<(Collection, Arc<[u64]>), SendmeError> {
+    let (_hash_seq, sizes) = get_hash_seq_and_sizes(connection, hash, 1024 * 1024 * 32)
+        .await
+        .map_err(SendmeError::request)?;
+    // get the hash seq and the metadata blob, which contains the names
+    let request = GetRequest::new(
+        *hash,
+        RangeSpecSeq::from_ranges([ChunkRanges::all(), ChunkRanges::all()]),
+    );
+    let connected = fsm::start(connection.clone(), request)
+        .next()
+        .await
+        .map_err(GetError::from)?;
+    let fsm::ConnectedNext::StartRoot(start) = connected.next().await.map_err(GetError::from)?
+    else {
+        let cause = anyhow::anyhow!("expected the collection root");
+        return Err(SendmeError::Request(GetError::NoncompliantNode(cause)));
     };
-    connect_progress.finish_and_clear();
-    let (send, recv) = async_channel::bounded(32);
-    let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
-    let (_hash_seq, sizes) =
-        get_hash_seq_and_sizes(&connection, &hash_and_format.hash, 1024 * 1024 * 32)
-            .await
-            .map_err(show_get_error)?;
+    let (next, _links, collection) = Collection::read_fsm(start)
+        .await
+        .map_err(SendmeError::request)?;
+    if let fsm::EndBlobNext::Closing(closing) = next {
+        closing.next().await.map_err(GetError::from)?;
+    }
+    Ok((collection, sizes))
+}
+
+/// Fail if there is not enough free space in `dir` for `size` bytes.
+fn check_free_space(dir: &Path, size: u64) -> anyhow::Result<()> {
+    let available = match fs4::available_space(dir) {
+        Ok(available) => available,
+        Err(cause) => {
+            // not knowing is no reason to refuse the download
+            tracing::debug!("failed to get free space of {}: {cause}", dir.display());
+            return Ok(());
+        }
+    };
+    if size > available {
+        return Err(exit::with_code(
+            exit::NO_SPACE,
+            anyhow::anyhow!(
+                "not enough free space in {}: need {}, but only {} available",
+                dir.display(),
+                HumanBytes(size),
+                HumanBytes(available)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The size of the files in `dir`, 0 if it does not exist.
+fn dir_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Ask the user whether to download a collection, if stdin is a terminal.
+///
+/// Shows the number of files, the total size and the top level names.
+async fn confirm_download(collection: &Collection, payload_size: u64) -> anyhow::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    // the files and directories that will be created in the current directory
+    let top_level = collection
+        .iter()
+        .filter_map(|(name, _)| name.split('/').next())
+        .collect::<BTreeSet<_>>();
+    const MAX_NAMES: usize = 5;
+    for name in top_level.iter().take(MAX_NAMES) {
+        eprintln!("    {name}");
+    }
+    if top_level.len() > MAX_NAMES {
+        eprintln!("    ... and {} more", top_level.len() - MAX_NAMES);
+    }
+    eprint!(
+        "Download {} files, {}? [y/N] ",
+        collection.len(),
+        HumanBytes(payload_size)
+    );
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map(|_| answer)
+    })
+    .await??;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Show which node a device name belongs to, and ask whether to send to it if stdin is a terminal.
+async fn confirm_recipient(name: &str, node_id: &NodeId) -> anyhow::Result<bool> {
+    eprintln!("{name} is node {node_id}");
+    if !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    eprint!("Send to it? [y/N] ");
+    let answer = tokio::task::spawn_blocking(|| {
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer).map(|_| answer)
+    })
+    .await??;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Print the names and sizes of the files in the collection of a ticket.
+///
+/// Only the collection metadata is downloaded, nothing is written to disk.
+async fn list_ticket(ticket: BlobTicket, common: &CommonArgs) -> anyhow::Result<()> {
+    let endpoint = receive_endpoint(&ticket, common, None).await?;
+    let connection = endpoint
+        .connect(ticket.node_addr().clone(), iroh_blobs::protocol::ALPN)
+        .await
+        .map_err(|cause| SendmeError::connect(ticket.node_addr().node_id, cause))?;
+    let (collection, sizes) = get_collection_and_sizes(&connection, &ticket.hash()).await?;
+    let mut reporter = EventReporter::new(common.json_progress);
+    // the first size is the one of the metadata blob
+    for ((name, hash), size) in collection.iter().zip(sizes.iter().skip(1)) {
+        reporter.report_now(ChildEvent::File {
+            name: name.clone(),
+            size: *size,
+        });
+        if common.verbose > 0 {
+            println!(
+                "{} {} {name}",
+                print_hash(hash, common.format),
+                HumanBytes(*size)
+            );
+        } else {
+            println!("{} {name}", HumanBytes(*size));
+        }
+    }
+    println!(
+        "{} files, {}",
+        collection.len(),
+        HumanBytes(sizes.iter().skip(1).sum::<u64>())
+    );
+    endpoint.close().await;
+    Ok(())
+}
+
+/// Download and export the collection of a ticket, filling in the details of the history entry.
+async fn receive_ticket(
+    ticket: BlobTicket,
+    common: &CommonArgs,
+    yes: bool,
+    limit: &Arc<limit::RateLimit>,
+    retry: &RetryArgs,
+    entry: &mut history::Entry,
+) -> anyhow::Result<()> {
+    let endpoint = receive_endpoint(&ticket, common, Some(limit)).await?;
+    let res = download(&endpoint, ticket, common, yes, limit, retry, entry).await;
+    // tell the sender that we are done, instead of letting the connection time out
+    endpoint.close().await;
+    res
+}
+
+/// Describe a collection before downloading it, and check that it can and should be downloaded.
+///
+/// The first size is the one of the collection metadata blob.
+async fn prepare_download(
+    hash: &Hash,
+    collection: &Collection,
+    sizes: &[u64],
+    common: &CommonArgs,
+    yes: bool,
+    entry: &mut history::Entry,
+) -> anyhow::Result<()> {
+    if let Some(name) = collection_name(collection) {
+        entry.name = name;
+    }
     let total_size = sizes.iter().sum::<u64>();
     let total_files = sizes.len().saturating_sub(1);
     let payload_size = sizes.iter().skip(1).sum::<u64>();
+    entry.size = payload_size;
     eprintln!(
         "getting collection {} {} files, {}",
-        print_hash(&ticket.hash(), args.common.format),
+        print_hash(hash, common.format),
         total_files,
         HumanBytes(payload_size)
     );
     // print the details of the collection only in verbose mode
-    if args.common.verbose > 0 {
+    if common.verbose > 0 {
         eprintln!(
             "getting {} blobs in total, {}",
             sizes.len(),
             HumanBytes(total_size)
         );
     }
-    let _task = tokio::spawn(show_download_progress(recv, total_size));
-    let get_conn = || async move { Ok(connection) };
-    let stats = iroh_blobs::get::db::get_to_db(&db, get_conn, &hash_and_format, progress)
-        .await
-        .map_err(|e| show_get_error(anyhow::anyhow!(e)))?;
-    let collection = Collection::load_db(&db, &hash_and_format.hash).await?;
-    if args.common.verbose > 0 {
+    let dir = std::env::current_dir()?;
+    // refuse names that can not be exported before downloading anything
+    for (name, _) in collection.iter() {
+        get_export_path(&dir, name).with_context(|| format!("invalid file name {name:?}"))?;
+    }
+    // a resumed download only needs room for what it does not have yet, and
+    // exporting moves the data out of the store on the same file system
+    let stored = dir_size(&dir.join(format!(".sendme-get-{}", hash.to_hex())));
+    check_free_space(&dir, total_size.saturating_sub(stored))?;
+    if !yes && !confirm_download(collection, payload_size).await? {
+        return Err(exit::with_code(
+            exit::ABORTED,
+            anyhow::anyhow!("download cancelled"),
+        ));
+    }
+    Ok(())
+}
+
+/// Connect to the sender of a ticket, retrying if it can not be reached.
+async fn connect(
+    endpoint: &Endpoint,
+    addr: &NodeAddr,
+    retries: &mut Retries,
+) -> anyhow::Result<Connection> {
+    loop {
+        // connecting can take long, but not longer than retrying may
+        let attempt = endpoint.connect(addr.clone(), iroh_blobs::protocol::ALPN);
+        match tokio::time::timeout_at(retries.deadline, attempt).await {
+            Ok(Ok(connection)) => return Ok(connection),
+            Ok(Err(cause)) => {
+                retries
+                    .wait(SendmeError::connect(addr.node_id, cause).into())
+                    .await?
+            }
+            Err(elapsed) => {
+                retries
+                    .wait(SendmeError::connect(addr.node_id, elapsed).into())
+                    .await?
+            }
+        }
+    }
+}
+
+/// Download and export the collection of a ticket using an existing endpoint.
+///
+/// If the connection fails, the download is resumed on a new one, keeping
+/// the data that was already downloaded and verified.
+async fn download(
+    endpoint: &Endpoint,
+    ticket: BlobTicket,
+    common: &CommonArgs,
+    yes: bool,
+    limit: &Arc<limit::RateLimit>,
+    retry: &RetryArgs,
+    entry: &mut history::Entry,
+) -> anyhow::Result<()> {
+    let addr = ticket.node_addr().clone();
+    let node_id = addr.node_id;
+    let mut retries = Retries::new(retry);
+    let mp = MultiProgress::new();
+    let connect_progress = mp.add(ProgressBar::hidden());
+    connect_progress.set_draw_target(ProgressDrawTarget::stderr());
+    connect_progress.set_style(ProgressStyle::default_spinner());
+    connect_progress.set_message(format!("connecting to {}", addr.node_id));
+    let mut connection = connect(endpoint, &addr, &mut retries).await?;
+    let mut _limit = limit.limit_receive(connection.clone());
+    // show when the connection goes from the relay to a direct one
+    let _path_changes = (common.verbose > 0)
+        .then(|| doctor::log_path_changes(endpoint, node_id))
+        .flatten();
+    let hash_and_format = HashAndFormat {
+        hash: ticket.hash(),
+        format: ticket.format(),
+    };
+    connect_progress.finish_and_clear();
+    let (send, recv) = async_channel::bounded(32);
+    let progress = iroh_blobs::util::progress::AsyncChannelProgressSender::new(send);
+    let (collection, sizes) = loop {
+        match get_collection_and_sizes(&connection, &hash_and_format.hash).await {
+            Ok(res) => break res,
+            Err(cause) => {
+                retries.wait(cause.into()).await?;
+                connection = connect(endpoint, &addr, &mut retries).await?;
+                _limit = limit.limit_receive(connection.clone());
+            }
+        }
+    };
+    prepare_download(&ticket.hash(), &collection, &sizes, common, yes, entry).await?;
+    let total_size = sizes.iter().sum::<u64>();
+    let total_files = sizes.len().saturating_sub(1);
+    let payload_size = sizes.iter().skip(1).sum::<u64>();
+    let dir_name = format!(".sendme-get-{}", ticket.hash().to_hex());
+    let iroh_data_dir = std::env::current_dir()?.join(dir_name);
+    let lock = gc::lock(&iroh_data_dir)?;
+    let db = iroh_blobs::store::fs::Store::load(&iroh_data_dir).await?;
+    let _task = tokio::spawn(show_download_progress(
+        recv,
+        total_size,
+        common.json_progress,
+    ));
+    // the store keeps what was verified, so each attempt only gets the rest
+    let stats = loop {
+        let get_conn = {
+            let connection = connection.clone();
+            || async move { Ok(connection) }
+        };
+        match iroh_blobs::get::db::get_to_db(&db, get_conn, &hash_and_format, progress.clone())
+            .await
+        {
+            Ok(stats) => break stats,
+            Err(cause) => {
+                retries.wait(SendmeError::from(cause).into()).await?;
+                connection = connect(endpoint, &addr, &mut retries).await?;
+                _limit = limit.limit_receive(connection.clone());
+            }
+        }
+    };
+    if common.verbose > 0 {
         for (name, hash) in collection.iter() {
-            println!("    {} {name}", print_hash(hash, args.common.format));
+            println!("    {} {name}", print_hash(hash, common.format));
         }
     }
     if let Some((name, _)) = collection.iter().next() {
@@ -853,8 +2266,9 @@ async fn receive(args: ReceiveArgs) -> anyhow::Result<()> {
         }
     }
     export(db, collection).await?;
+    drop(lock);
     tokio::fs::remove_dir_all(iroh_data_dir).await?;
-    if args.common.verbose > 0 {
+    if common.verbose > 0 {
         println!(
             "downloaded {} files, {}. took {} ({}/s)",
             total_files,
@@ -866,8 +2280,191 @@ async fn receive(args: ReceiveArgs) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Share the data on the daemon, which keeps serving it after we exit.
+async fn send_with_daemon(mut client: daemon::Client, args: SendArgs) -> anyhow::Result<()> {
+    let path = std::path::absolute(&args.path)?;
+    let request = daemon::Request::Send {
+        path: path.clone(),
+        ticket_type: args.ticket_type,
+        expires: None,
+    };
+    let daemon::Response::Shared { share, ticket } = client.request(&request).await? else {
+        anyhow::bail!("unexpected response from the daemon");
+    };
+    let entry_type = if path.is_file() { "file" } else { "directory" };
+    println!(
+        "shared {} {} on the daemon, {}, hash {}",
+        entry_type,
+        path.display(),
+        HumanBytes(share.size),
+        print_hash(&share.hash, args.common.format)
+    );
+    println!("to get this data, use");
+    println!("sendme receive {}", ticket);
+    if args.qr {
+        println!("{}", qr::render_unicode(&ticket.to_string())?);
+    }
+    Ok(())
+}
+
+async fn shares_command(args: SharesArgs) -> anyhow::Result<()> {
+    match args.command {
+        SharesCommand::List => ls().await,
+        SharesCommand::Add(args) => share_add(args).await,
+        SharesCommand::Remove(args) => stop(args).await,
+    }
+}
+
+async fn share_add(args: ShareAddArgs) -> anyhow::Result<()> {
+    let mut client = daemon::connect().await?;
+    let expires = args
+        .expire
+        .map(|expire| chrono::Duration::from_std(expire.into()))
+        .transpose()?
+        .map(|expire| chrono::Utc::now() + expire);
+    let request = daemon::Request::Send {
+        path: std::path::absolute(&args.path)?,
+        ticket_type: args.ticket_type,
+        expires,
+    };
+    let daemon::Response::Shared { share, ticket } = client.request(&request).await? else {
+        anyhow::bail!("unexpected response from the daemon");
+    };
+    println!(
+        "shared {}, {}, hash {}",
+        share.path.display(),
+        HumanBytes(share.size),
+        print_hash(&share.hash, Format::Hex)
+    );
+    println!("sendme receive {}", ticket);
+    Ok(())
+}
+
+async fn ls() -> anyhow::Result<()> {
+    let mut client = daemon::connect().await?;
+    let daemon::Response::Shares { shares } = client.request(&daemon::Request::List).await? else {
+        anyhow::bail!("unexpected response from the daemon");
+    };
+    if shares.is_empty() {
+        println!("nothing shared");
+    }
+    for (share, ticket) in shares {
+        let expires = match share.expires {
+            Some(expires) => {
+                let left = (expires - chrono::Utc::now()).to_std().unwrap_or_default();
+                // whole seconds, the rest is noise
+                let left = Duration::from_secs(left.as_secs());
+                format!(", expires in {}", humantime::format_duration(left))
+            }
+            None => String::new(),
+        };
+        println!(
+            "{} {} {}, {} downloads{}",
+            print_hash(&share.hash, Format::Hex),
+            share.path.display(),
+            HumanBytes(share.size),
+            share.downloads,
+            expires
+        );
+        println!("    sendme receive {}", ticket);
+    }
+    Ok(())
+}
+
+async fn stop(args: StopArgs) -> anyhow::Result<()> {
+    let mut client = daemon::connect().await?;
+    client
+        .request(&daemon::Request::Stop { hash: args.hash })
+        .await?;
+    println!("stopped sharing {}", print_hash(&args.hash, Format::Hex));
+    Ok(())
+}
+
+async fn nearby(args: NearbyArgs) -> anyhow::Result<()> {
+    let browser = mdns::MdnsDiscovery::browse()?;
+    tokio::time::sleep(args.timeout.into()).await;
+    let peers = browser.peers();
+    if peers.is_empty() {
+        println!("no sendme nodes found on the local network");
+    }
+    for peer in &peers {
+        if peer.device.listening {
+            println!("{} {}, receiving", peer.device.name, peer.node_id);
+            // a name that several devices have does not tell them apart
+            let same_name = peers
+                .iter()
+                .filter(|other| other.device.name.eq_ignore_ascii_case(&peer.device.name))
+                .count();
+            if same_name > 1 {
+                println!("    sendme send <path> --to {}", peer.node_id);
+            } else {
+                println!("    sendme send <path> --to {}", peer.device.name);
+            }
+        } else {
+            println!("{} {}", peer.device.name, peer.node_id);
+        }
+    }
+    Ok(())
+}
+
+fn key_command(args: KeyArgs) -> anyhow::Result<()> {
+    match args.command {
+        KeyCommand::Generate { force } => {
+            let key = SecretKey::generate(rand::rngs::OsRng);
+            let path = key::store(&key, force)?;
+            println!("node id {}", key.public());
+            eprintln!("key stored in {}", path.display());
+        }
+        KeyCommand::Show => {
+            let key = key::load()?.context("no key stored, use sendme key generate")?;
+            println!("node id {}", key.public());
+            eprintln!("key stored in {}", key::key_file()?.display());
+        }
+        KeyCommand::Import { key, force } => {
+            let text = match key {
+                Some(key) => key,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let key = SecretKey::from_str(text.trim()).context("invalid secret key")?;
+            let path = key::store(&key, force)?;
+            println!("node id {}", key.public());
+            eprintln!("key stored in {}", path.display());
+        }
+        KeyCommand::Export => {
+            let key = key::load()?.context("no key stored, use sendme key generate")?;
+            println!("{key}");
+        }
+    }
+    Ok(())
+}
+
+mod config;
+mod daemon;
+mod doctor;
+mod error;
+mod exit;
+mod gc;
 pub mod gui;
-use gui::run_gui;
+mod hashcache;
+mod history;
+mod inbox;
+mod key;
+mod limit;
+mod mdns;
+mod multi;
+mod qr;
+use error::SendmeError;
+use gui::{run_gui, ChildEvent, EventReporter};
+use hashcache::HashCache;
+
+/// Whether a command line error is about a ticket that could not be parsed.
+fn is_bad_ticket(cause: &clap::Error) -> bool {
+    cause.kind() == ErrorKind::ValueValidation
+        && matches!(
+            cause.get(ContextKind::InvalidArg),
+            Some(ContextValue::String(arg)) if arg.contains("TICKET") || arg.starts_with("--from")
+        )
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -878,10 +2475,25 @@ async fn main() -> anyhow::Result<()> {
         // Default to GUI mode when no arguments are provided
         Args {
             command: Commands::Gui,
+            config: None,
+            profile: None,
         }
     } else {
-        // Otherwise parse arguments normally
-        match Args::try_parse() {
+        // Otherwise parse arguments normally, with defaults from the config file
+        let argv = std::env::args_os().collect::<Vec<_>>();
+        let command = match config::load(&argv)
+            .and_then(|options| config::apply(Args::command(), &options))
+        {
+            Ok(command) => command,
+            Err(cause) => {
+                eprintln!("invalid config: {cause:#}");
+                std::process::exit(exit::USAGE);
+            }
+        };
+        match command
+            .try_get_matches_from(argv)
+            .and_then(|matches| Args::from_arg_matches(&matches))
+        {
             Ok(args) => args,
             Err(cause) => {
                 if let Some(text) = cause.get(ContextKind::InvalidSubcommand) {
@@ -891,6 +2503,9 @@ async fn main() -> anyhow::Result<()> {
                         eprintln!("    {}", style(cmd.get_name()).bold());
                     }
                     std::process::exit(1);
+                } else if is_bad_ticket(&cause) {
+                    let _ = cause.print();
+                    std::process::exit(exit::BAD_TICKET);
                 } else {
                     cause.exit();
                 }
@@ -898,16 +2513,45 @@ async fn main() -> anyhow::Result<()> {
         }
     };
     
+    // the GUI wants to know how a transfer failed
+    let json_progress = match &args.command {
+        Commands::Send(args) => args.common.json_progress,
+        Commands::Receive(args) => args.common.json_progress,
+        _ => false,
+    };
     let res = match args.command {
         Commands::Send(args) => send(args).await,
         Commands::Receive(args) => receive(args).await,
+        Commands::Key(args) => key_command(args),
+        Commands::Daemon(args) => daemon::run(args.common).await,
+        Commands::Shares(args) => shares_command(args).await,
+        Commands::Ls => ls().await,
+        Commands::Stop(args) => stop(args).await,
+        Commands::Inbox(args) => {
+            let limit = limit::RateLimit::new(None);
+            inbox::run(args.common, inbox::Policy::Inbox, limit).await
+        }
+        Commands::Push(args) => {
+            let limit = limit::RateLimit::new(None);
+            inbox::push(
+                args.ticket.node_addr().clone(),
+                args.path,
+                args.common,
+                limit,
+            )
+            .await
+        }
+        Commands::Nearby(args) => nearby(args).await,
+        Commands::Doctor(args) => doctor::run(args).await,
+        Commands::Gc(args) => gc::run(args).await,
         Commands::Gui => run_gui(),
     };
     if let Err(e) = &res {
-        eprintln!("{e}");
+        eprintln!("{e:#}");
+        EventReporter::new(json_progress).report_now(ChildEvent::failed(e));
     }
     match res {
         Ok(()) => std::process::exit(0),
-        Err(_) => std::process::exit(1),
+        Err(e) => std::process::exit(exit::code(&e)),
     }
 }
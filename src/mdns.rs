@@ -0,0 +1,349 @@
+//! Discovery of sendme nodes on the local network with mDNS.
+//!
+//! Every node announces itself as a `_sendme._udp` service, with its node id,
+//! direct addresses and relay url in the TXT record, and browses for the
+//! announcements of the others. This way tickets with just a node id work on a
+//! network without internet access, where neither the relays nor the DNS
+//! discovery are reachable.
+//!
+//! The announcement also contains a device name for people to recognize, and
+//! whether the node waits for data with `sendme receive --listen`. This is what
+//! `sendme nearby` and the GUI show, so data can be sent to a device on the
+//! same network without exchanging a ticket at all.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures_lite::{stream, StreamExt};
+use iroh::{
+    discovery::{Discovery, DiscoveryItem, NodeData, NodeInfo},
+    Endpoint, NodeAddr, NodeId, RelayUrl, SecretKey,
+};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use n0_future::boxed::BoxStream;
+use tokio::sync::broadcast;
+
+/// The mDNS service type sendme nodes announce themselves as.
+const SERVICE_TYPE: &str = "_sendme._udp.local.";
+
+/// The provenance of the discovered addresses, shown in the endpoint's logs.
+const PROVENANCE: &str = "mdns";
+
+/// How long to wait for a node to show up on the local network.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for other devices with the same name, once one was found.
+const SAME_NAME_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a node tells the others about itself, besides its addresses.
+#[derive(Debug, Clone, Default)]
+pub struct Device {
+    /// A name for people to recognize the device by.
+    pub name: String,
+    /// Whether the node accepts data sent with `sendme send --to`.
+    pub listening: bool,
+}
+
+impl Device {
+    /// Describe this device, using the host name if no name is given.
+    pub fn new(name: Option<String>, listening: bool) -> Self {
+        let name =
+            name.unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned());
+        Self { name, listening }
+    }
+}
+
+/// A node found on the local network.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub node_id: NodeId,
+    pub device: Device,
+    /// The full mDNS name of the announcement, to handle its removal.
+    fullname: String,
+    info: NodeInfo,
+}
+
+/// Announces our node on the local network and finds the others.
+pub struct MdnsDiscovery {
+    node_id: NodeId,
+    device: Device,
+    daemon: ServiceDaemon,
+    peers: Arc<Mutex<BTreeMap<NodeId, Peer>>>,
+    updates: broadcast::Sender<Peer>,
+}
+
+impl std::fmt::Debug for MdnsDiscovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MdnsDiscovery")
+            .field("node_id", &self.node_id)
+            .field("device", &self.device)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MdnsDiscovery {
+    /// Start browsing for other nodes. Our own node is announced once the endpoint publishes it.
+    pub fn new(node_id: NodeId, device: Device) -> anyhow::Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let events = daemon.browse(SERVICE_TYPE)?;
+        let peers: Arc<Mutex<BTreeMap<NodeId, Peer>>> = Default::default();
+        let (updates, _) = broadcast::channel(64);
+        // the events end when the daemon is shut down
+        std::thread::spawn({
+            let peers = peers.clone();
+            let updates = updates.clone();
+            move || {
+                while let Ok(event) = events.recv() {
+                    match event {
+                        ServiceEvent::ServiceResolved(service) => {
+                            let Some(peer) = parse_service(&service) else {
+                                continue;
+                            };
+                            if peer.node_id == node_id {
+                                continue;
+                            }
+                            tracing::debug!("found {} on the local network", peer.node_id);
+                            peers.lock().unwrap().insert(peer.node_id, peer.clone());
+                            updates.send(peer).ok();
+                        }
+                        ServiceEvent::ServiceRemoved(_, fullname) => {
+                            peers
+                                .lock()
+                                .unwrap()
+                                .retain(|_, peer| peer.fullname != fullname);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            node_id,
+            device,
+            daemon,
+            peers,
+            updates,
+        })
+    }
+
+    /// Only look for other nodes, without ever announcing one.
+    pub fn browse() -> anyhow::Result<Self> {
+        let node_id = SecretKey::generate(rand::rngs::OsRng).public();
+        Self::new(node_id, Device::default())
+    }
+
+    /// The nodes found so far, sorted by name.
+    pub fn peers(&self) -> Vec<Peer> {
+        let mut peers = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.device.name.cmp(&b.device.name));
+        peers
+    }
+
+    /// Wait for a node to show up, until the timeout.
+    async fn find(&self, matches: impl Fn(&Peer) -> bool) -> Option<Peer> {
+        // subscribe first, so nothing found in between is missed
+        let mut updates = self.updates.subscribe();
+        if let Some(peer) = self.peers().into_iter().find(&matches) {
+            return Some(peer);
+        }
+        let deadline = tokio::time::Instant::now() + RESOLVE_TIMEOUT;
+        loop {
+            match tokio::time::timeout_at(deadline, updates.recv()).await {
+                Ok(Ok(peer)) if matches(&peer) => return Some(peer),
+                Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return None,
+            }
+        }
+    }
+}
+
+/// Create the discovery for the endpoint builder, logging instead of failing if mDNS is not available.
+pub fn discovery(node_id: NodeId, device: Device) -> Option<MdnsDiscovery> {
+    match MdnsDiscovery::new(node_id, device) {
+        Ok(discovery) => Some(discovery),
+        Err(cause) => {
+            tracing::warn!("local discovery is not available: {cause}");
+            None
+        }
+    }
+}
+
+/// Find the address of a listening device on the local network by its name.
+///
+/// Fails if several devices have that name, since the data would go to any of them.
+pub async fn find_listener(name: &str) -> anyhow::Result<NodeAddr> {
+    let browser = MdnsDiscovery::browse()?;
+    let matches =
+        |peer: &Peer| peer.device.listening && peer.device.name.eq_ignore_ascii_case(name);
+    let peer = browser
+        .find(matches)
+        .await
+        .with_context(|| format!("no device named {name} is listening on the local network"))?;
+    // the others answer at about the same time
+    tokio::time::sleep(SAME_NAME_TIMEOUT).await;
+    let node_ids = browser
+        .peers()
+        .into_iter()
+        .filter(matches)
+        .map(|peer| peer.node_id.to_string())
+        .collect::<BTreeSet<_>>();
+    if node_ids.len() > 1 {
+        anyhow::bail!(
+            "{} devices named {name} are listening, use --to with one of their node ids: {}",
+            node_ids.len(),
+            node_ids.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+    Ok(peer.info.into_node_addr())
+}
+
+/// Read a peer from the TXT record of an announcement.
+fn parse_service(service: &ServiceInfo) -> Option<Peer> {
+    let node_id = service
+        .get_property_val_str("node")?
+        .parse::<NodeId>()
+        .ok()?;
+    let relay_url = service
+        .get_property_val_str("relay")
+        .and_then(|url| url.parse::<RelayUrl>().ok());
+    let direct_addresses = service
+        .get_properties()
+        .iter()
+        .filter(|property| property.key().starts_with("addr."))
+        .filter_map(|property| property.val_str().parse::<SocketAddr>().ok())
+        .collect::<BTreeSet<_>>();
+    let device = Device {
+        name: service
+            .get_property_val_str("name")
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| node_id.fmt_short()),
+        listening: service.get_property_val_str("listen") == Some("1"),
+    };
+    Some(Peer {
+        node_id,
+        device,
+        fullname: service.get_fullname().to_string(),
+        info: NodeInfo::new(node_id)
+            .with_relay_url(relay_url)
+            .with_direct_addresses(direct_addresses),
+    })
+}
+
+impl Discovery for MdnsDiscovery {
+    fn publish(&self, data: &NodeData) {
+        let mut properties = vec![
+            ("node".to_string(), self.node_id.to_string()),
+            ("name".to_string(), self.device.name.clone()),
+        ];
+        if self.device.listening {
+            properties.push(("listen".to_string(), "1".to_string()));
+        }
+        if let Some(relay_url) = data.relay_url() {
+            properties.push(("relay".to_string(), relay_url.to_string()));
+        }
+        for (i, addr) in data.direct_addresses().iter().enumerate() {
+            properties.push((format!("addr.{i}"), addr.to_string()));
+        }
+        let port = data
+            .direct_addresses()
+            .first()
+            .map(|addr| addr.port())
+            .unwrap_or_default();
+        let name = self.node_id.fmt_short();
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &name,
+            &format!("sendme-{name}.local."),
+            "",
+            port,
+            properties.as_slice(),
+        )
+        .map(ServiceInfo::enable_addr_auto);
+        // registering the same name again replaces the old announcement
+        if let Err(cause) = service.and_then(|service| self.daemon.register(service)) {
+            tracing::warn!("failed to announce on the local network: {cause}");
+        }
+    }
+
+    fn resolve(
+        &self,
+        _endpoint: Endpoint,
+        node_id: NodeId,
+    ) -> Option<BoxStream<anyhow::Result<DiscoveryItem>>> {
+        // the node might have been found already, otherwise wait for it
+        let known = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(&node_id)
+            .map(|peer| peer.info.clone());
+        let updates = self.updates.subscribe();
+        let deadline = tokio::time::Instant::now() + RESOLVE_TIMEOUT;
+        let found = stream::unfold(updates, move |mut updates| async move {
+            loop {
+                match tokio::time::timeout_at(deadline, updates.recv()).await {
+                    Ok(Ok(peer)) if peer.node_id == node_id => return Some((peer.info, updates)),
+                    Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                    Ok(Err(broadcast::error::RecvError::Closed)) | Err(_) => return None,
+                }
+            }
+        });
+        let items = stream::iter(known)
+            .chain(found)
+            .map(|info| Ok(DiscoveryItem::new(info, PROVENANCE, None)));
+        Some(Box::pin(items))
+    }
+}
+
+/// Wraps a discovery service, so that its errors do not stop the search.
+///
+/// The endpoint gives up on all discovery services as soon as one of them
+/// fails. On a network without internet access the DNS discovery fails right
+/// away, before the nodes on the local network had a chance to answer.
+#[derive(Debug)]
+pub struct IgnoreErrors<D>(pub D);
+
+impl<D: Discovery> Discovery for IgnoreErrors<D> {
+    fn publish(&self, data: &NodeData) {
+        self.0.publish(data)
+    }
+
+    fn resolve(
+        &self,
+        endpoint: Endpoint,
+        node_id: NodeId,
+    ) -> Option<BoxStream<anyhow::Result<DiscoveryItem>>> {
+        let items = self
+            .0
+            .resolve(endpoint, node_id)?
+            .filter(|item| match item {
+                Ok(_) => true,
+                Err(cause) => {
+                    tracing::debug!("discovery failed: {cause}");
+                    false
+                }
+            });
+        Some(Box::pin(items))
+    }
+
+    fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
+        self.0.subscribe()
+    }
+}
+
+impl Drop for MdnsDiscovery {
+    fn drop(&mut self) {
+        self.daemon.shutdown().ok();
+    }
+}
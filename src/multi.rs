@@ -0,0 +1,491 @@
+//! Downloading a collection from several senders at once.
+//!
+//! `sendme receive <hash> --from <ticket> --from <ticket>` connects to the
+//! senders of all tickets, which must have the same collection. The files are
+//! split into pieces of a few MiB, and each sender is asked for the next piece
+//! nobody has started yet, so a faster sender does more of the work. If a
+//! sender goes away, the piece it was working on goes back to the others.
+//!
+//! Every piece is verified against the hash of its file, so one sender can not
+//! spoil the data that came from the others. A download that is resumed only
+//! asks for the parts of the pieces that are not in the store yet.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io,
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use indicatif::HumanBytes;
+use iroh::{endpoint::Connection, Endpoint};
+use iroh_blobs::{
+    get::{
+        db::{BlobId, DownloadProgress},
+        error::GetError,
+        fsm, Stats,
+    },
+    protocol::{GetRequest, RangeSpecSeq},
+    store::{
+        bao_tree::{
+            io::{fsm::valid_ranges, BaoContentItem},
+            ChunkNum, ChunkRanges,
+        },
+        fs::Store,
+        BaoBatchWriter, EntryStatus, MapEntry, MapEntryMut, MapMut,
+    },
+    ticket::BlobTicket,
+    Hash,
+};
+use n0_future::StreamExt;
+use tokio::sync::Notify;
+
+use crate::{error::SendmeError, history, limit::RateLimit, CommonArgs};
+
+/// The size of the pieces files are split into, in chunks of 1 KiB.
+const PIECE_CHUNKS: u64 = 4096;
+
+/// A part of a file, downloaded from one sender.
+#[derive(Debug, Clone)]
+struct Piece {
+    /// The id for progress reports.
+    id: u64,
+    hash: Hash,
+    /// The position of the file in the collection.
+    child: NonZeroU64,
+    start: ChunkNum,
+    /// The chunks of the piece that are not in the store yet.
+    ranges: ChunkRanges,
+    /// The number of bytes in the piece.
+    size: u64,
+}
+
+/// The pieces that are left to download, shared by all senders.
+#[derive(Debug)]
+struct Pieces {
+    state: Mutex<PiecesState>,
+    /// Notified whenever a piece is done.
+    changed: Notify,
+}
+
+#[derive(Debug)]
+struct PiecesState {
+    /// The pieces nobody has started yet.
+    queue: VecDeque<Piece>,
+    /// The pieces in progress, with the number of senders working on each.
+    started: BTreeMap<u64, (Piece, usize)>,
+}
+
+impl Pieces {
+    /// Split the files into pieces, skipping files that are in the collection more than once.
+    ///
+    /// `stored` are the chunks of each file that are in the store already.
+    /// Returns the pieces to download, and the ones that are complete.
+    fn new(
+        files: impl IntoIterator<Item = (NonZeroU64, Hash, u64)>,
+        stored: &BTreeMap<Hash, ChunkRanges>,
+    ) -> (Self, Vec<Piece>) {
+        let mut queue = VecDeque::new();
+        let mut complete = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut id = 0;
+        for (child, hash, size) in files {
+            if !seen.insert(hash) {
+                continue;
+            }
+            let chunks = size.div_ceil(1024);
+            let mut start = 0;
+            loop {
+                let end = start + PIECE_CHUNKS;
+                let last = end >= chunks;
+                let ranges = match last {
+                    true => ChunkRanges::from(ChunkNum(start)..),
+                    false => ChunkRanges::from(ChunkNum(start)..ChunkNum(end)),
+                };
+                let ranges = match stored.get(&hash) {
+                    Some(stored) => ranges.difference(stored),
+                    None => ranges,
+                };
+                let piece = Piece {
+                    id,
+                    hash,
+                    child,
+                    start: ChunkNum(start),
+                    size: (end * 1024).min(size) - start * 1024,
+                    ranges,
+                };
+                id += 1;
+                match piece.ranges.is_empty() {
+                    true => complete.push(piece),
+                    false => queue.push_back(piece),
+                }
+                if last {
+                    break;
+                }
+                start = end;
+            }
+        }
+        let pieces = Self {
+            state: Mutex::new(PiecesState {
+                queue,
+                started: BTreeMap::new(),
+            }),
+            changed: Notify::new(),
+        };
+        (pieces, complete)
+    }
+
+    /// The next piece to download, or `None` once all pieces are done.
+    ///
+    /// Once every piece is started, a sender with nothing to do also starts
+    /// the piece that the fewest senders work on. This way a sender that went
+    /// away without a word can not hold up the end of the download, and
+    /// whoever is done first wins.
+    fn next(&self) -> Option<Piece> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(piece) = state.queue.pop_front() {
+            state.started.insert(piece.id, (piece.clone(), 1));
+            return Some(piece);
+        }
+        let (piece, senders) = state
+            .started
+            .values_mut()
+            .min_by_key(|(_, senders)| *senders)?;
+        *senders += 1;
+        Some(piece.clone())
+    }
+
+    fn done(&self, piece: &Piece) {
+        self.state.lock().unwrap().started.remove(&piece.id);
+        self.changed.notify_waiters();
+    }
+
+    /// Give up on a piece, leaving it to another sender if nobody else works on it.
+    fn failed(&self, piece: &Piece) {
+        let mut state = self.state.lock().unwrap();
+        let Some((_, senders)) = state.started.get_mut(&piece.id) else {
+            return;
+        };
+        *senders -= 1;
+        if *senders == 0 {
+            state.started.remove(&piece.id);
+            state.queue.push_front(piece.clone());
+        }
+    }
+
+    /// Wait until another sender is done with a piece.
+    async fn finished(&self, piece: &Piece) {
+        loop {
+            // before looking, so a change in between is not missed
+            let changed = self.changed.notified();
+            if !self.state.lock().unwrap().started.contains_key(&piece.id) {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    fn left(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.queue.len() + state.started.len()
+    }
+}
+
+/// Download and export a collection from the senders of the tickets.
+pub async fn download(
+    endpoint: &Endpoint,
+    hash: Hash,
+    tickets: &[BlobTicket],
+    common: &CommonArgs,
+    yes: bool,
+    limit: &Arc<RateLimit>,
+    entry: &mut history::Entry,
+) -> anyhow::Result<()> {
+    eprintln!("connecting to {} senders", tickets.len());
+    let connections = futures_buffered::join_all(tickets.iter().map(|ticket| async move {
+        let node_id = ticket.node_addr().node_id;
+        let res = endpoint
+            .connect(ticket.node_addr().clone(), iroh_blobs::protocol::ALPN)
+            .await
+            .map_err(|cause| SendmeError::connect(node_id, cause));
+        (node_id, res)
+    }))
+    .await
+    .into_iter()
+    .filter_map(|(node_id, res)| match res {
+        Ok(connection) => Some((node_id, connection)),
+        Err(cause) => {
+            eprintln!("{cause:#}");
+            None
+        }
+    })
+    .collect::<Vec<_>>();
+    if connections.is_empty() {
+        return Err(crate::exit::with_code(
+            crate::exit::SENDER_UNREACHABLE,
+            anyhow::anyhow!("none of the senders can be reached"),
+        ));
+    }
+
+    // any sender can tell what is in the collection
+    let mut found = None;
+    for (node_id, connection) in &connections {
+        match crate::get_collection_and_sizes(connection, &hash).await {
+            Ok(res) => {
+                found = Some(res);
+                break;
+            }
+            Err(cause) => eprintln!("failed to get the collection from {node_id}: {cause:#}"),
+        }
+    }
+    let Some((collection, sizes)) = found else {
+        return Err(crate::exit::with_code(
+            crate::exit::NOT_FOUND,
+            anyhow::anyhow!("none of the senders has the collection"),
+        ));
+    };
+    crate::prepare_download(&hash, &collection, &sizes, common, yes, entry).await?;
+    let payload_size = sizes.iter().skip(1).sum::<u64>();
+    // the first size is the one of the metadata blob
+    let files = collection
+        .iter()
+        .zip(sizes.iter().skip(1))
+        .enumerate()
+        .map(|(i, ((_, hash), size))| (NonZeroU64::MIN.saturating_add(i as u64), *hash, *size))
+        .collect::<Vec<_>>();
+
+    let iroh_data_dir = std::env::current_dir()?.join(format!(".sendme-get-{}", hash.to_hex()));
+    let lock = crate::gc::lock(&iroh_data_dir)?;
+    let db = Store::load(&iroh_data_dir).await?;
+    let (send, recv) = async_channel::bounded(32);
+    let _task = tokio::spawn(crate::show_download_progress(
+        recv,
+        payload_size,
+        common.json_progress,
+    ));
+    send.send(DownloadProgress::Connected).await?;
+    send.send(DownloadProgress::FoundHashSeq {
+        hash,
+        children: files.len() as u64,
+    })
+    .await?;
+
+    // what is left of an earlier attempt does not have to be downloaded again
+    let mut stored = BTreeMap::new();
+    for (_, hash, _) in &files {
+        stored.insert(*hash, stored_ranges(&db, hash).await?);
+    }
+    let (pieces, complete) = Pieces::new(files.iter().copied(), &stored);
+    for piece in complete {
+        send.send(DownloadProgress::Found {
+            id: piece.id,
+            child: BlobId::Child(piece.child),
+            hash: piece.hash,
+            size: piece.size,
+        })
+        .await?;
+        send.send(DownloadProgress::Done { id: piece.id }).await?;
+    }
+    let start = Instant::now();
+    let results = futures_buffered::join_all(connections.iter().map(|(node_id, connection)| {
+        let (db, pieces, send) = (&db, &pieces, &send);
+        async move {
+            let _limit = limit.limit_receive(connection.clone());
+            (*node_id, provide(connection, db, pieces, send).await)
+        }
+    }))
+    .await;
+    let mut stats = Stats {
+        elapsed: start.elapsed(),
+        ..Default::default()
+    };
+    for (node_id, res) in &results {
+        match res {
+            Ok(provided) => {
+                stats.bytes_written += provided.bytes_written;
+                stats.bytes_read += provided.bytes_read;
+                if common.verbose > 0 {
+                    eprintln!(
+                        "downloaded {} from {node_id}",
+                        HumanBytes(provided.bytes_read)
+                    );
+                }
+            }
+            Err(cause) => eprintln!("stopped downloading from {node_id}: {cause:#}"),
+        }
+    }
+    anyhow::ensure!(
+        pieces.left() == 0,
+        "all senders failed, {} pieces are missing",
+        pieces.left()
+    );
+    // each piece was verified on its own, so the files are complete now
+    for (_, hash, size) in &files {
+        let entry = db.get_or_create(*hash, *size).await?;
+        db.insert_complete(entry).await?;
+    }
+    send.send(DownloadProgress::AllDone(stats)).await?;
+
+    if let Some((name, _)) = collection.iter().next() {
+        if let Some(first) = name.split('/').next() {
+            println!("downloading to: {};", first);
+        }
+    }
+    crate::export(db, collection).await?;
+    drop(lock);
+    tokio::fs::remove_dir_all(iroh_data_dir).await?;
+    Ok(())
+}
+
+/// The chunks of a blob that are in the store, checked against its hash.
+///
+/// The outboard alone is not enough, the data of a download that was killed
+/// may not have made it to the disk.
+async fn stored_ranges(db: &Store, hash: &Hash) -> io::Result<ChunkRanges> {
+    let entry = match db.entry_status(hash).await? {
+        EntryStatus::Complete => return Ok(ChunkRanges::all()),
+        EntryStatus::Partial => db.get_mut(hash).await?,
+        EntryStatus::NotFound => None,
+    };
+    let Some(entry) = entry else {
+        return Ok(ChunkRanges::empty());
+    };
+    let all = ChunkRanges::all();
+    let outboard = MapEntry::outboard(&entry).await?;
+    let data = MapEntry::data_reader(&entry).await?;
+    let ranges = valid_ranges(outboard, data, &all);
+    let mut ranges = std::pin::pin!(ranges);
+    let mut valid = ChunkRanges::empty();
+    while let Some(range) = ranges.next().await {
+        valid |= ChunkRanges::from(range?);
+    }
+    Ok(valid)
+}
+
+/// Download pieces from one sender until there are none left, or the sender fails.
+async fn provide(
+    connection: &Connection,
+    db: &Store,
+    pieces: &Pieces,
+    progress: &async_channel::Sender<DownloadProgress>,
+) -> Result<Stats, SendmeError> {
+    let mut total = Stats::default();
+    while let Some(piece) = pieces.next() {
+        tokio::select! {
+            res = fetch(connection, db, &piece, progress) => match res {
+                Ok(stats) => {
+                    total.bytes_written += stats.bytes_written;
+                    total.bytes_read += stats.bytes_read;
+                    pieces.done(&piece);
+                }
+                Err(cause) => {
+                    pieces.failed(&piece);
+                    return Err(cause);
+                }
+            },
+            // another sender was faster
+            _ = pieces.finished(&piece) => {}
+        }
+    }
+    Ok(total)
+}
+
+/// Download a single piece into the store.
+async fn fetch(
+    connection: &Connection,
+    db: &Store,
+    piece: &Piece,
+    progress: &async_channel::Sender<DownloadProgress>,
+) -> Result<Stats, SendmeError> {
+    let request = GetRequest::new(
+        piece.hash,
+        RangeSpecSeq::from_ranges([piece.ranges.clone()]),
+    );
+    let connected = fsm::start(connection.clone(), request)
+        .next()
+        .await
+        .map_err(GetError::from)?;
+    let fsm::ConnectedNext::StartRoot(start) = connected.next().await.map_err(GetError::from)?
+    else {
+        let cause = anyhow::anyhow!("expected the blob");
+        return Err(SendmeError::Request(GetError::NoncompliantNode(cause)));
+    };
+    let (content, size) = start.next().next().await.map_err(GetError::from)?;
+    let entry = db
+        .get_or_create(piece.hash, size)
+        .await
+        .map_err(|cause| GetError::LocalFailure(cause.into()))?;
+    progress
+        .send(DownloadProgress::Found {
+            id: piece.id,
+            child: BlobId::Child(piece.child),
+            hash: piece.hash,
+            size: piece.size,
+        })
+        .await
+        .map_err(|cause| GetError::LocalFailure(cause.into()))?;
+    let mut writer = ProgressWriter {
+        inner: entry
+            .batch_writer()
+            .await
+            .map_err(|cause| GetError::LocalFailure(cause.into()))?,
+        id: piece.id,
+        start: piece.start.to_bytes(),
+        progress: progress.clone(),
+    };
+    let end = content.write_all_batch(&mut writer).await?;
+    writer
+        .sync()
+        .await
+        .map_err(|cause| GetError::LocalFailure(cause.into()))?;
+    let fsm::EndBlobNext::Closing(closing) = end.next() else {
+        let cause = anyhow::anyhow!("expected the end of the response");
+        return Err(SendmeError::Request(GetError::NoncompliantNode(cause)));
+    };
+    let stats = closing.next().await.map_err(GetError::from)?;
+    progress
+        .send(DownloadProgress::Done { id: piece.id })
+        .await
+        .map_err(|cause| GetError::LocalFailure(cause.into()))?;
+    Ok(stats)
+}
+
+/// Reports the progress of writing a piece.
+struct ProgressWriter<W> {
+    inner: W,
+    id: u64,
+    /// The offset of the piece in its file.
+    start: u64,
+    progress: async_channel::Sender<DownloadProgress>,
+}
+
+impl<W: BaoBatchWriter> BaoBatchWriter for ProgressWriter<W> {
+    async fn write_batch(&mut self, size: u64, batch: Vec<BaoContentItem>) -> io::Result<()> {
+        let end = batch
+            .iter()
+            .filter_map(|item| match item {
+                BaoContentItem::Leaf(leaf) => Some(leaf.offset + leaf.data.len() as u64),
+                _ => None,
+            })
+            .max();
+        self.inner.write_batch(size, batch).await?;
+        if let Some(end) = end {
+            let offset = end.saturating_sub(self.start);
+            // a full channel just means that this update is skipped
+            if let Err(async_channel::TrySendError::Closed(_)) =
+                self.progress.try_send(DownloadProgress::Progress {
+                    id: self.id,
+                    offset,
+                })
+            {
+                return Err(io::Error::other("the download was aborted"));
+            }
+        }
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync().await
+    }
+}
@@ -0,0 +1,115 @@
+//! Rendering tickets as QR codes and scanning them back from images.
+
+use std::{path::Path, str::FromStr};
+
+use anyhow::Context;
+use iroh_blobs::ticket::BlobTicket;
+use qrcode::{render::unicode, Color, QrCode};
+
+/// Render `data` as a QR code made of unicode half blocks, for printing to a terminal.
+///
+/// The colors are inverted so the code scans on the usual dark terminal background.
+pub fn render_unicode(data: &str) -> anyhow::Result<String> {
+    let code = QrCode::new(data)?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .quiet_zone(true)
+        .build())
+}
+
+/// Encode `data` as a QR code, returning the width and the modules in row major order.
+///
+/// A module is `true` if it is dark. The quiet zone is not included.
+pub fn modules(data: &str) -> anyhow::Result<(usize, Vec<bool>)> {
+    let code = QrCode::new(data)?;
+    let width = code.width();
+    let modules = code
+        .into_colors()
+        .into_iter()
+        .map(|c| c == Color::Dark)
+        .collect();
+    Ok((width, modules))
+}
+
+/// Scan an image file, e.g. a screenshot, for a QR code containing a ticket.
+///
+/// Both dark on light and light on dark codes are recognized, so a screenshot of
+/// the output of `sendme send --qr` in a dark terminal works as well.
+pub fn decode_ticket_from_image(path: &Path) -> anyhow::Result<BlobTicket> {
+    let image = image::open(path)
+        .with_context(|| format!("failed to open image {}", path.display()))?
+        .to_luma8();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    for invert in [false, true] {
+        let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(width, height, |x, y| {
+            let luma = image.get_pixel(x as u32, y as u32).0[0];
+            if invert {
+                255 - luma
+            } else {
+                luma
+            }
+        });
+        for grid in prepared.detect_grids() {
+            let content = match grid.decode() {
+                Ok((_, content)) => content,
+                Err(cause) => {
+                    tracing::debug!("failed to decode QR code: {cause}");
+                    continue;
+                }
+            };
+            // accept both a bare ticket and a full "sendme receive <ticket>" command line
+            let Some(text) = content.split_ascii_whitespace().last() else {
+                continue;
+            };
+            match BlobTicket::from_str(text) {
+                Ok(ticket) => return Ok(ticket),
+                Err(cause) => tracing::debug!("QR code does not contain a ticket: {cause}"),
+            }
+        }
+    }
+    anyhow::bail!("no QR code with a ticket found in {}", path.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh::{NodeAddr, SecretKey};
+    use iroh_blobs::{BlobFormat, Hash};
+
+    use super::*;
+
+    /// Draw `data` as a QR code with a quiet zone, like a screenshot would show it.
+    fn draw(data: &str, invert: bool) -> image::GrayImage {
+        const SCALE: usize = 4;
+        const QUIET: usize = 4;
+        let (width, modules) = modules(data).unwrap();
+        let size = (width + 2 * QUIET) * SCALE;
+        image::GrayImage::from_fn(size as u32, size as u32, |x, y| {
+            let (x, y) = (x as usize / SCALE, y as usize / SCALE);
+            let dark = (QUIET..QUIET + width).contains(&x)
+                && (QUIET..QUIET + width).contains(&y)
+                && modules[(y - QUIET) * width + x - QUIET];
+            image::Luma([if dark != invert { 0 } else { 255 }])
+        })
+    }
+
+    #[test]
+    fn ticket_round_trip() {
+        let addr = NodeAddr::new(SecretKey::generate(rand::rngs::OsRng).public());
+        let ticket = BlobTicket::new(addr, Hash::new(b"data"), BlobFormat::HashSeq).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cases = [
+            (ticket.to_string(), false),
+            (format!("sendme receive {ticket}"), false),
+            // as printed to a dark terminal
+            (ticket.to_string(), true),
+        ];
+        for (i, (data, invert)) in cases.into_iter().enumerate() {
+            let path = dir.path().join(format!("{i}.png"));
+            draw(&data, invert).save(&path).unwrap();
+            let decoded = decode_ticket_from_image(&path).unwrap();
+            assert_eq!(decoded, ticket);
+        }
+    }
+}
@@ -1,10 +1,10 @@
 use std::{
-    io::{self, Read},
+    io::{self, Read, Write},
     path::{Path, PathBuf},
     str::FromStr,
 };
 
-use iroh_blobs::ticket::BlobTicket;
+use iroh_blobs::{format::collection::Collection, store::Store, ticket::BlobTicket, BlobFormat};
 
 // binary path
 fn sendme_bin() -> &'static str {
@@ -35,6 +35,27 @@ fn read_ascii_lines(mut n: usize, reader: &mut impl Read) -> io::Result<Vec<u8>>
     Ok(res)
 }
 
+/// A sendme command with tracing disabled, that keeps its config, data and
+/// stores outside of the current directory in `data_dir` instead of the
+/// directories of the user.
+fn sendme_cmd(data_dir: &Path, args: &[&str]) -> duct::Expression {
+    duct::cmd(sendme_bin(), args)
+        .env_remove("RUST_LOG") // disable tracing
+        .env("SENDME_DATA_DIR", data_dir)
+        .env("SENDME_CONFIG_DIR", data_dir)
+        .env("XDG_RUNTIME_DIR", data_dir)
+}
+
+/// Read the first `lines` lines of `sendme send`, the last of which ends with the ticket.
+///
+/// Returns the lines and the ticket.
+fn read_ticket(lines: usize, reader: &mut impl Read) -> (String, String) {
+    let output = read_ascii_lines(lines, reader).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let ticket = output.split_ascii_whitespace().last().unwrap().to_string();
+    (output, ticket)
+}
+
 // fn wait2() -> Arc<Barrier> {
 //     Arc::new(Barrier::new(2))
 // }
@@ -51,24 +72,21 @@ fn send_recv_file() {
     // create src and tgt dir, and src file
     let src_dir = tempfile::tempdir().unwrap();
     let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
     let src_file = src_dir.path().join(name);
     std::fs::write(&src_file, &data).unwrap();
-    let mut send_cmd = duct::cmd(
-        sendme_bin(),
-        ["send", src_file.as_os_str().to_str().unwrap()],
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &["send", src_file.as_os_str().to_str().unwrap()],
     )
     .dir(src_dir.path())
-    .env_remove("RUST_LOG") // disable tracing
     .stderr_to_stdout()
     .reader()
     .unwrap();
-    let output = read_ascii_lines(3, &mut send_cmd).unwrap();
-    let output = String::from_utf8(output).unwrap();
-    let ticket = output.split_ascii_whitespace().last().unwrap();
-    let ticket = BlobTicket::from_str(ticket).unwrap();
-    let receive_output = duct::cmd(sendme_bin(), ["receive", &ticket.to_string()])
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let ticket = BlobTicket::from_str(&ticket).unwrap();
+    let receive_output = sendme_cmd(data_dir.path(), &["receive", &ticket.to_string()])
         .dir(tgt_dir.path())
-        .env_remove("RUST_LOG") // disable tracing
         .stderr_to_stdout()
         .run()
         .unwrap();
@@ -93,6 +111,7 @@ fn send_recv_dir() {
     // create src and tgt dir, and src file
     let src_dir = tempfile::tempdir().unwrap();
     let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
     let src_data_dir = src_dir.path().join("data");
     let tgt_data_dir = tgt_dir.path().join("data");
     // create a complex directory structure
@@ -105,22 +124,18 @@ fn send_recv_dir() {
             }
         }
     }
-    let mut send_cmd = duct::cmd(
-        sendme_bin(),
-        ["send", src_data_dir.as_os_str().to_str().unwrap()],
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &["send", src_data_dir.as_os_str().to_str().unwrap()],
     )
     .dir(src_dir.path())
-    .env_remove("RUST_LOG") // disable tracing
     .stderr_to_stdout()
     .reader()
     .unwrap();
-    let output = read_ascii_lines(3, &mut send_cmd).unwrap();
-    let output = String::from_utf8(output).unwrap();
-    let ticket = output.split_ascii_whitespace().last().unwrap();
-    let ticket = BlobTicket::from_str(ticket).unwrap();
-    let receive_output = duct::cmd(sendme_bin(), ["receive", &ticket.to_string()])
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let ticket = BlobTicket::from_str(&ticket).unwrap();
+    let receive_output = sendme_cmd(data_dir.path(), &["receive", &ticket.to_string()])
         .dir(tgt_dir.path())
-        .env_remove("RUST_LOG") // disable tracing
         .stderr_to_stdout()
         .run()
         .unwrap();
@@ -136,3 +151,1414 @@ fn send_recv_dir() {
         }
     }
 }
+
+#[test]
+fn send_list() {
+    let name = "somefile.bin";
+    let data = vec![0u8; 100];
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join(name);
+    std::fs::write(&src_file, &data).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &["send", src_file.as_os_str().to_str().unwrap()],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let ticket = BlobTicket::from_str(&ticket).unwrap();
+    let list_output = sendme_cmd(data_dir.path(), &["receive", &ticket.to_string(), "--list"])
+        .dir(tgt_dir.path())
+        .stdout_capture()
+        .run()
+        .unwrap();
+    assert!(list_output.status.success());
+    let stdout = String::from_utf8(list_output.stdout).unwrap();
+    assert!(stdout.contains("100 B somefile.bin"));
+    // listing must not download anything
+    assert_eq!(std::fs::read_dir(tgt_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn history_records_transfers() {
+    let name = "somefile.bin";
+    let data = vec![0u8; 100];
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    std::fs::write(src_dir.path().join(name), &data).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &["send", name, "--relay", "disabled", "--no-daemon"],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    sendme_cmd(
+        data_dir.path(),
+        &["receive", &ticket, "--relay", "disabled"],
+    )
+    .dir(tgt_dir.path())
+    .stderr_null()
+    .run()
+    .unwrap();
+    let history_file = data_dir.path().join("history.jsonl");
+    let find = |direction: &str| {
+        let history = std::fs::read_to_string(&history_file).unwrap_or_default();
+        history
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .find(|entry| entry["direction"] == direction)
+    };
+    let received = find("receive").unwrap();
+    assert_eq!(received["name"], name);
+    assert_eq!(received["size"], data.len());
+    assert_eq!(received["outcome"], "completed");
+    assert_eq!(received["ticket"], ticket);
+    // the sender records the transfer once it sees it complete, which may be a bit later
+    let start = std::time::Instant::now();
+    let sent = loop {
+        if let Some(sent) = find("send") {
+            break sent;
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    };
+    send_cmd.kill().unwrap();
+    assert_eq!(sent["name"], name);
+    assert_eq!(sent["hash"], received["hash"]);
+    assert_eq!(sent["outcome"], "completed");
+}
+
+#[test]
+fn key_generate_and_send() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let config_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("somefile.bin");
+    std::fs::write(&src_file, vec![0u8; 100]).unwrap();
+    let generate_output = sendme_cmd(config_dir.path(), &["key", "generate"])
+        .stdout_capture()
+        .run()
+        .unwrap();
+    let stdout = String::from_utf8(generate_output.stdout).unwrap();
+    let node_id = stdout.split_ascii_whitespace().last().unwrap().to_string();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = std::fs::metadata(config_dir.path().join("secret.key")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+    // a second generate must not overwrite the key
+    let regenerate_output = sendme_cmd(config_dir.path(), &["key", "generate"])
+        .stderr_capture()
+        .unchecked()
+        .run()
+        .unwrap();
+    assert!(!regenerate_output.status.success());
+    // send must use the stored key, unless another send is using it
+    let send = |dir: &Path| {
+        let mut send_cmd = sendme_cmd(
+            config_dir.path(),
+            &[
+                "send",
+                src_file.as_os_str().to_str().unwrap(),
+                "--relay",
+                "disabled",
+            ],
+        )
+        .dir(dir)
+        .stderr_to_stdout()
+        .reader()
+        .unwrap();
+        let (_, ticket) = read_ticket(3, &mut send_cmd);
+        (send_cmd, BlobTicket::from_str(&ticket).unwrap())
+    };
+    let (first_cmd, first_ticket) = send(src_dir.path());
+    let (second_cmd, second_ticket) = send(src_dir.path());
+    assert_eq!(first_ticket.node_addr().node_id.to_string(), node_id);
+    // two processes with the same node id would fight over the relay
+    assert_ne!(second_ticket.node_addr().node_id.to_string(), node_id);
+    // the receiver can not use the stored key either, but connects anyway
+    let tgt_dir = tempfile::tempdir().unwrap();
+    sendme_cmd(
+        config_dir.path(),
+        &["receive", &first_ticket.to_string(), "--relay", "disabled"],
+    )
+    .dir(tgt_dir.path())
+    .stderr_null()
+    .run()
+    .unwrap();
+    first_cmd.kill().unwrap();
+    second_cmd.kill().unwrap();
+    // once the first send is gone, the key is free again, and a new directory
+    // avoids the notice about the stores the killed sends left behind
+    let other_dir = tempfile::tempdir().unwrap();
+    let (third_cmd, third_ticket) = send(other_dir.path());
+    third_cmd.kill().unwrap();
+    assert_eq!(third_ticket.node_addr().node_id.to_string(), node_id);
+    assert_eq!(
+        std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+        vec![0u8; 100]
+    );
+}
+
+#[test]
+fn send_with_config_profile() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let config_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("somefile.bin");
+    std::fs::write(&src_file, vec![0u8; 100]).unwrap();
+    let config_file = config_dir.path().join("custom.toml");
+    std::fs::write(
+        &config_file,
+        "ticket-type = \"addresses\"\n\n[profile.short]\nticket-type = \"id\"\n",
+    )
+    .unwrap();
+    let mut send_cmd = sendme_cmd(
+        config_dir.path(),
+        &[
+            "send",
+            src_file.as_os_str().to_str().unwrap(),
+            "--config",
+            config_file.as_os_str().to_str().unwrap(),
+            "--profile",
+            "short",
+        ],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let ticket = BlobTicket::from_str(&ticket).unwrap();
+    // the profile overrides the top level option
+    assert!(ticket.node_addr().direct_addresses.is_empty());
+    assert!(ticket.node_addr().relay_url.is_none());
+    drop(send_cmd);
+    // an invalid config file is a usage error, like invalid options
+    std::fs::write(&config_file, "ticket-type = \n").unwrap();
+    let output = sendme_cmd(
+        config_dir.path(),
+        &[
+            "send",
+            src_file.as_os_str().to_str().unwrap(),
+            "--config",
+            config_file.as_os_str().to_str().unwrap(),
+        ],
+    )
+    .dir(src_dir.path())
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("invalid config"));
+}
+
+#[test]
+fn send_with_conflicting_relays() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("somefile.bin");
+    std::fs::write(&src_file, vec![0u8; 100]).unwrap();
+    let output = sendme_cmd(
+        data_dir.path(),
+        &[
+            "send",
+            src_file.as_os_str().to_str().unwrap(),
+            "--relay",
+            "https://relay1.example.com,https://relay2.example.com",
+            "--relay",
+            "disabled",
+        ],
+    )
+    .dir(src_dir.path())
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("--relay disabled can not be combined with other relays"));
+}
+
+#[cfg(unix)]
+#[test]
+fn daemon_shares() {
+    use nix::{
+        sys::signal::{self, Signal},
+        unistd::Pid,
+    };
+
+    let name = "somefile.bin";
+    let data = vec![0u8; 100];
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join(name);
+    std::fs::write(&src_file, &data).unwrap();
+    // whether the data can be received with the ticket, into an empty directory
+    let receive = |ticket: &BlobTicket| {
+        let tgt_dir = tempfile::tempdir().unwrap();
+        sendme_cmd(
+            data_dir.path(),
+            &["receive", &ticket.to_string(), "--retries", "0"],
+        )
+        .dir(tgt_dir.path())
+        .stderr_null()
+        .stdout_null()
+        .unchecked()
+        .run()
+        .unwrap()
+        .status
+        .success()
+    };
+    let mut daemon = sendme_cmd(data_dir.path(), &["daemon"])
+        .stdout_capture()
+        .reader()
+        .unwrap();
+    // the daemon prints two lines once it is ready
+    read_ascii_lines(2, &mut daemon).unwrap();
+    // the daemon can not serve with the options of the sender
+    let refused = sendme_cmd(
+        data_dir.path(),
+        &[
+            "send",
+            src_file.as_os_str().to_str().unwrap(),
+            "--daemon",
+            "--relay",
+            "disabled",
+        ],
+    )
+    .stderr_null()
+    .stdout_null()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert!(!refused.status.success());
+    let send_output = sendme_cmd(
+        data_dir.path(),
+        &["send", src_file.as_os_str().to_str().unwrap(), "--daemon"],
+    )
+    .read()
+    .unwrap();
+    // send returns right away, the daemon serves the data
+    let ticket = send_output.split_ascii_whitespace().last().unwrap();
+    let ticket = BlobTicket::from_str(ticket).unwrap();
+    sendme_cmd(data_dir.path(), &["receive", &ticket.to_string()])
+        .dir(tgt_dir.path())
+        .stderr_null()
+        .run()
+        .unwrap();
+    assert_eq!(std::fs::read(tgt_dir.path().join(name)).unwrap(), data);
+    let ls_output = sendme_cmd(data_dir.path(), &["ls"]).read().unwrap();
+    assert!(ls_output.contains(&ticket.hash().to_hex().to_string()));
+    assert!(ls_output.contains("1 downloads"));
+    sendme_cmd(data_dir.path(), &["stop", &ticket.hash().to_hex()])
+        .run()
+        .unwrap();
+    let ls_output = sendme_cmd(data_dir.path(), &["ls"]).read().unwrap();
+    assert!(!ls_output.contains(&ticket.hash().to_hex().to_string()));
+    // a stopped share can not be downloaded any more
+    assert!(!receive(&ticket));
+    // a share with an expiry goes away by itself
+    sendme_cmd(
+        data_dir.path(),
+        &[
+            "shares",
+            "add",
+            src_file.as_os_str().to_str().unwrap(),
+            "--expire",
+            "1s",
+        ],
+    )
+    .run()
+    .unwrap();
+    let ls_output = sendme_cmd(data_dir.path(), &["shares", "list"])
+        .read()
+        .unwrap();
+    assert!(ls_output.contains(&ticket.hash().to_hex().to_string()));
+    assert!(receive(&ticket));
+    std::thread::sleep(std::time::Duration::from_secs(3));
+    let ls_output = sendme_cmd(data_dir.path(), &["shares", "list"])
+        .read()
+        .unwrap();
+    assert!(!ls_output.contains(&ticket.hash().to_hex().to_string()));
+    assert!(!receive(&ticket));
+    // sharing a file again after it changed hashes it again
+    let share = |path: &Path| {
+        let output = sendme_cmd(data_dir.path(), &["shares", "add", path.to_str().unwrap()])
+            .read()
+            .unwrap();
+        BlobTicket::from_str(output.split_ascii_whitespace().last().unwrap()).unwrap()
+    };
+    let old_ticket = share(&src_file);
+    assert_eq!(old_ticket.hash(), ticket.hash());
+    let new_data = vec![1u8; 200];
+    std::fs::write(&src_file, &new_data).unwrap();
+    let new_ticket = share(&src_file);
+    assert_ne!(new_ticket.hash(), ticket.hash());
+    assert!(!receive(&old_ticket));
+    let new_tgt_dir = tempfile::tempdir().unwrap();
+    sendme_cmd(data_dir.path(), &["receive", &new_ticket.to_string()])
+        .dir(new_tgt_dir.path())
+        .stderr_null()
+        .run()
+        .unwrap();
+    assert_eq!(
+        std::fs::read(new_tgt_dir.path().join(name)).unwrap(),
+        new_data
+    );
+    for pid in daemon.pids() {
+        signal::kill(Pid::from_raw(pid as i32), Signal::SIGINT).unwrap();
+    }
+    let mut rest = String::new();
+    daemon.read_to_string(&mut rest).unwrap();
+    assert!(!data_dir.path().join("daemon.sock").exists());
+}
+
+#[test]
+fn inbox_push() {
+    let name = "somefile.bin";
+    let data = vec![1u8; 1000];
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join(name);
+    std::fs::write(&src_file, &data).unwrap();
+    let mut inbox = sendme_cmd(data_dir.path(), &["inbox"])
+        .dir(tgt_dir.path())
+        .stdout_capture()
+        .reader()
+        .unwrap();
+    let output = read_ascii_lines(3, &mut inbox).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    // the last line is "sendme push <ticket> <path>"
+    let ticket = output.split_ascii_whitespace().rev().nth(1).unwrap();
+    let push_output = sendme_cmd(
+        data_dir.path(),
+        &["push", ticket, src_file.as_os_str().to_str().unwrap()],
+    )
+    .dir(src_dir.path())
+    .stderr_null()
+    .read()
+    .unwrap();
+    assert!(push_output.contains("received"));
+    assert_eq!(std::fs::read(tgt_dir.path().join(name)).unwrap(), data);
+    // the temporary stores are cleaned up on both sides
+    assert_eq!(std::fs::read_dir(src_dir.path()).unwrap().count(), 1);
+    assert_eq!(std::fs::read_dir(tgt_dir.path()).unwrap().count(), 1);
+}
+
+/// Push a collection with a single file called `name` to the inbox at `addr`,
+/// like `sendme push` does, and return the response of the inbox.
+///
+/// `sendme push` never makes names like `../x`, so the collection is made here.
+fn push_collection(addr: iroh::NodeAddr, name: &str) -> serde_json::Value {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let endpoint = iroh::Endpoint::builder()
+            .relay_mode(iroh::RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let blobs = iroh_blobs::net_protocol::Blobs::memory().build(&endpoint);
+        let router = iroh::protocol::Router::builder(endpoint.clone())
+            .accept(iroh_blobs::ALPN, blobs.clone())
+            .spawn()
+            .await
+            .unwrap();
+        let data = blobs
+            .store()
+            .import_bytes(bytes::Bytes::from_static(b"pushed"), BlobFormat::Raw)
+            .await
+            .unwrap();
+        let collection: Collection = [(name, *data.hash())].into_iter().collect();
+        let tag = collection.store(blobs.store()).await.unwrap();
+        endpoint.direct_addresses().initialized().await.unwrap();
+        let ticket = BlobTicket::new(
+            endpoint.node_addr().await.unwrap(),
+            *tag.hash(),
+            BlobFormat::HashSeq,
+        )
+        .unwrap();
+        let conn = endpoint.connect(addr, b"/sendme/inbox/0").await.unwrap();
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(&serde_json::to_vec(&ticket).unwrap())
+            .await
+            .unwrap();
+        send.finish().unwrap();
+        let response = recv.read_to_end(64 * 1024).await.unwrap();
+        conn.close(0u32.into(), b"done");
+        router.shutdown().await.unwrap();
+        serde_json::from_slice(&response).unwrap()
+    })
+}
+
+#[test]
+fn inbox_rejects_names_outside() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = dir.path().join("inbox");
+    std::fs::create_dir(&tgt_dir).unwrap();
+    let mut inbox = sendme_cmd(data_dir.path(), &["inbox", "--relay", "disabled"])
+        .dir(&tgt_dir)
+        .stdout_capture()
+        .stderr_null()
+        .reader()
+        .unwrap();
+    let output = read_ascii_lines(3, &mut inbox).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let ticket = output.split_ascii_whitespace().rev().nth(1).unwrap();
+    let addr = iroh_base::ticket::NodeTicket::from_str(ticket)
+        .unwrap()
+        .node_addr()
+        .clone();
+    for name in ["../x", "a/../../x", "./x", "a//x"] {
+        let response = push_collection(addr.clone(), name);
+        assert_eq!(response["response"], "failed", "{name}");
+    }
+    assert!(!dir.path().join("x").exists());
+    // nothing was exported, and the downloads were cleaned up
+    assert_eq!(std::fs::read_dir(&tgt_dir).unwrap().count(), 0);
+}
+
+#[test]
+fn listener_rejects_names_outside() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = dir.path().join("listener");
+    std::fs::create_dir(&tgt_dir).unwrap();
+    // a fixed address, since the listener only prints its node id
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let bind = format!("127.0.0.1:{port}");
+    // --yes accepts everything without asking, so only the name check is left
+    let mut listener = sendme_cmd(
+        data_dir.path(),
+        &[
+            "receive",
+            "--listen",
+            "--yes",
+            "--relay",
+            "disabled",
+            "--magic-ipv4-addr",
+            &bind,
+        ],
+    )
+    .dir(&tgt_dir)
+    .stdout_capture()
+    .stderr_null()
+    .reader()
+    .unwrap();
+    let output = read_ascii_lines(3, &mut listener).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let node_id = output.split_ascii_whitespace().last().unwrap();
+    let addr = iroh::NodeAddr::new(node_id.parse().unwrap())
+        .with_direct_addresses([bind.parse().unwrap()]);
+    let response = push_collection(addr.clone(), "../x");
+    assert_eq!(response["response"], "failed");
+    assert!(!dir.path().join("x").exists());
+    // a plain name still gets through
+    let response = push_collection(addr, "x");
+    assert_eq!(response["response"], "received");
+    assert_eq!(std::fs::read(tgt_dir.join("x")).unwrap(), b"pushed");
+}
+
+#[test]
+fn send_recv_local_discovery() {
+    let name = "somefile.bin";
+    let data = vec![2u8; 100];
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join(name);
+    std::fs::write(&src_file, &data).unwrap();
+    // without relays, a ticket with just the node id can only be resolved locally
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &[
+            "send",
+            src_file.as_os_str().to_str().unwrap(),
+            "--ticket-type",
+            "id",
+            "--relay",
+            "disabled",
+            "--local-discovery",
+        ],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let ticket = BlobTicket::from_str(&ticket).unwrap();
+    assert!(ticket.node_addr().direct_addresses.is_empty());
+    sendme_cmd(
+        data_dir.path(),
+        &[
+            "receive",
+            &ticket.to_string(),
+            "--relay",
+            "disabled",
+            "--local-discovery",
+        ],
+    )
+    .dir(tgt_dir.path())
+    .stderr_null()
+    .run()
+    .unwrap();
+    assert_eq!(std::fs::read(tgt_dir.path().join(name)).unwrap(), data);
+}
+
+#[test]
+fn send_to_nearby_device() {
+    let name = "somefile.bin";
+    let data = vec![3u8; 100];
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join(name);
+    std::fs::write(&src_file, &data).unwrap();
+    // a unique name, so other tests or machines on the network don't interfere
+    let device = format!("device-{}", rand::random::<u32>());
+    // start a listener, returning it, its directory and its node id
+    let listen = || {
+        let tgt_dir = tempfile::tempdir().unwrap();
+        let mut listener = sendme_cmd(
+            data_dir.path(),
+            &[
+                "receive",
+                "--listen",
+                "--yes",
+                "--relay",
+                "disabled",
+                "--local-discovery",
+                "--device-name",
+                &device,
+            ],
+        )
+        .dir(tgt_dir.path())
+        .stdout_capture()
+        .reader()
+        .unwrap();
+        let output = read_ascii_lines(3, &mut listener).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let node_id = output.split_ascii_whitespace().last().unwrap().to_string();
+        (listener, tgt_dir, node_id)
+    };
+    let send = |to: &str| {
+        sendme_cmd(
+            data_dir.path(),
+            &[
+                "send",
+                src_file.as_os_str().to_str().unwrap(),
+                "--to",
+                to,
+                "--relay",
+                "disabled",
+                "--local-discovery",
+            ],
+        )
+        .dir(src_dir.path())
+        .stderr_capture()
+        .stdout_capture()
+        .unchecked()
+    };
+    let (_listener, tgt_dir, node_id) = listen();
+    let nearby_output = sendme_cmd(data_dir.path(), &["nearby"]).read().unwrap();
+    assert!(nearby_output.contains(&format!("sendme send <path> --to {device}")));
+    // anyone can claim a name, so the node id is asked about on a terminal
+    #[cfg(unix)]
+    {
+        let pty = nix::pty::openpty(None, None).unwrap();
+        let mut terminal = std::fs::File::from(pty.master);
+        terminal.write_all(b"n\n").unwrap();
+        let send_output = send(&device)
+            .stdin_file(std::fs::File::from(pty.slave))
+            .run()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&send_output.stderr);
+        assert!(
+            stderr.contains(&format!("{device} is node {node_id}")),
+            "{stderr}"
+        );
+        assert!(stderr.contains("send cancelled"), "{stderr}");
+        assert_eq!(send_output.status.code(), Some(8));
+        assert!(!tgt_dir.path().join(name).exists());
+    }
+    let send_output = send(&device).stdin_null().run().unwrap();
+    let stderr = String::from_utf8_lossy(&send_output.stderr);
+    assert!(
+        stderr.contains(&format!("{device} is node {node_id}")),
+        "{stderr}"
+    );
+    let stdout = String::from_utf8_lossy(&send_output.stdout);
+    assert!(stdout.contains("received"), "{stdout}");
+    assert_eq!(std::fs::read(tgt_dir.path().join(name)).unwrap(), data);
+    // with two devices of the same name, the node id has to pick one
+    let (_other_listener, other_tgt_dir, other_node_id) = listen();
+    let send_output = send(&device).stdin_null().run().unwrap();
+    let stderr = String::from_utf8_lossy(&send_output.stderr);
+    assert!(!send_output.status.success());
+    assert!(stderr.contains(&other_node_id), "{stderr}");
+    let send_output = send(&other_node_id).run().unwrap();
+    assert!(send_output.status.success());
+    assert_eq!(
+        std::fs::read(other_tgt_dir.path().join(name)).unwrap(),
+        data
+    );
+}
+
+#[test]
+fn doctor_reports_path() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("somefile.bin");
+    std::fs::write(&src_file, vec![4u8; 100_000]).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &[
+            "send",
+            src_file.as_os_str().to_str().unwrap(),
+            "--relay",
+            "disabled",
+        ],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let doctor_output = sendme_cmd(data_dir.path(), &["doctor", &ticket, "--relay", "disabled"])
+        .read()
+        .unwrap();
+    // without relays, the connection can only be direct
+    assert!(doctor_output.contains("path: direct"));
+    assert!(doctor_output.contains("downloaded "));
+}
+
+#[test]
+fn send_recv_limit_rate() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let src_file = src_dir.path().join("somefile.bin");
+    let data = (0..1_000_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    std::fs::write(&src_file, &data).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &[
+            "send",
+            src_file.as_os_str().to_str().unwrap(),
+            "--relay",
+            "disabled",
+        ],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let start = std::time::Instant::now();
+    sendme_cmd(
+        data_dir.path(),
+        &[
+            "receive",
+            &ticket,
+            "--relay",
+            "disabled",
+            "--limit-rate",
+            "500KB/s",
+        ],
+    )
+    .dir(tgt_dir.path())
+    .run()
+    .unwrap();
+    // a megabyte at 500KB/s takes about two seconds
+    assert!(start.elapsed() >= std::time::Duration::from_millis(1500));
+    assert_eq!(
+        std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+        data
+    );
+}
+
+#[test]
+fn send_limit_rate_shared_by_receivers() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let data = (0..1_000_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    std::fs::write(src_dir.path().join("somefile.bin"), &data).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &[
+            "send",
+            "somefile.bin",
+            "--relay",
+            "disabled",
+            "--no-daemon",
+            "--limit-rate",
+            "500KB/s",
+        ],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let tgt_dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let start = std::time::Instant::now();
+    let receivers = tgt_dirs
+        .iter()
+        .map(|tgt_dir| {
+            sendme_cmd(
+                data_dir.path(),
+                &["receive", &ticket, "--relay", "disabled"],
+            )
+            .dir(tgt_dir.path())
+            .stderr_null()
+            .stdout_null()
+            .start()
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    for receiver in receivers {
+        receiver.wait().unwrap();
+    }
+    send_cmd.kill().unwrap();
+    // two megabytes at 500KB/s take about four seconds, however they are split
+    assert!(start.elapsed() >= std::time::Duration::from_millis(3000));
+    for tgt_dir in &tgt_dirs {
+        assert_eq!(
+            std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+            data
+        );
+    }
+}
+
+#[test]
+fn recv_from_multiple_senders() {
+    let src_dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let data_dir = tempfile::tempdir().unwrap();
+    let data = (0..10_000_000u32)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    // the same file in two places gives the same collection, the limit gives
+    // the receiver time to lose one of the senders
+    let mut tickets = Vec::new();
+    let mut send_cmds = Vec::new();
+    for src_dir in &src_dirs {
+        std::fs::write(src_dir.path().join("somefile.bin"), &data).unwrap();
+        let mut send_cmd = sendme_cmd(
+            data_dir.path(),
+            &[
+                "send",
+                "somefile.bin",
+                "--relay",
+                "disabled",
+                "--no-daemon",
+                "--limit-rate",
+                "2MB/s",
+            ],
+        )
+        .dir(src_dir.path())
+        .stderr_to_stdout()
+        .reader()
+        .unwrap();
+        let (_, ticket) = read_ticket(3, &mut send_cmd);
+        tickets.push(ticket);
+        send_cmds.push(send_cmd);
+    }
+    let node_ids = tickets
+        .iter()
+        .map(|ticket| {
+            let ticket = ticket.parse::<BlobTicket>().unwrap();
+            ticket.node_addr().node_id.to_string()
+        })
+        .collect::<Vec<_>>();
+    let hash = tickets[0].parse::<BlobTicket>().unwrap().hash().to_string();
+    let receive = |tgt_dir: &Path| {
+        sendme_cmd(
+            data_dir.path(),
+            &[
+                "receive",
+                &hash,
+                "--from",
+                &tickets[0],
+                "--from",
+                &tickets[1],
+                "--relay",
+                "disabled",
+                "-v",
+            ],
+        )
+        .dir(tgt_dir)
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .start()
+        .unwrap()
+    };
+    // both senders do a part of the work
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let output = receive(tgt_dir.path()).into_output().unwrap();
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(output.contains("connecting to 2 senders"), "{output}");
+    for node_id in &node_ids {
+        let line = output
+            .lines()
+            .find(|line| line.ends_with(&format!("from {node_id}")))
+            .unwrap_or_else(|| panic!("nothing from {node_id}: {output}"));
+        assert!(!line.starts_with("downloaded 0 B"), "{output}");
+    }
+    assert_eq!(
+        std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+        data
+    );
+    // a download that is resumed only gets what it does not have yet
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let recv_cmd = receive(tgt_dir.path());
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    recv_cmd.kill().unwrap();
+    let output = receive(tgt_dir.path()).into_output().unwrap();
+    let output = String::from_utf8_lossy(&output.stdout);
+    let transferred = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Transferred "))
+        .and_then(|line| {
+            let mut words = line.split(' ');
+            let size = words.next()?.parse::<f64>().ok()?;
+            let unit = match words.next()? {
+                "B" => 1,
+                "KiB" => 1 << 10,
+                "MiB" => 1 << 20,
+                _ => return None,
+            };
+            Some((size * unit as f64) as usize)
+        })
+        .unwrap_or_else(|| panic!("no transferred size: {output}"));
+    assert!(transferred < data.len() * 9 / 10, "{output}");
+    assert_eq!(
+        std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+        data
+    );
+    // the other sender takes over from one that goes away
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let recv_cmd = receive(tgt_dir.path());
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    send_cmds[1].kill().unwrap();
+    let output = recv_cmd.wait().unwrap();
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{text}");
+    assert_eq!(
+        std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+        data
+    );
+    // the other senders take over instead of retrying
+    let output = sendme_cmd(
+        data_dir.path(),
+        &["receive", &hash, "--from", &tickets[0], "--retries", "2"],
+    )
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
+
+#[test]
+fn recv_unreachable_sender() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    std::fs::write(src_dir.path().join("somefile.bin"), vec![1u8; 1000]).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &["send", "somefile.bin", "--relay", "disabled", "--no-daemon"],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    send_cmd.kill().unwrap();
+    let output = sendme_cmd(
+        data_dir.path(),
+        &[
+            "receive",
+            &ticket,
+            "--relay",
+            "disabled",
+            "--retry-timeout",
+            "3s",
+        ],
+    )
+    .dir(tgt_dir.path())
+    .stderr_to_stdout()
+    .stdout_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(text.contains("the sender can not be reached"), "{text}");
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn recv_exit_codes() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    std::fs::write(src_dir.path().join("somefile.bin"), vec![1u8; 1000]).unwrap();
+    // the target already has a file with the same name
+    std::fs::write(tgt_dir.path().join("somefile.bin"), b"mine").unwrap();
+    let sendme = |args: &[&str]| {
+        sendme_cmd(data_dir.path(), args)
+            .dir(tgt_dir.path())
+            .stderr_to_stdout()
+            .stdout_capture()
+            .unchecked()
+    };
+    let output = sendme(&["receive", "notaticket"]).run().unwrap();
+    assert_eq!(output.status.code(), Some(9));
+    let mut send_cmd = sendme(&["send", "somefile.bin", "--relay", "disabled", "--no-daemon"])
+        .dir(src_dir.path())
+        .reader()
+        .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let output = sendme(&["receive", &ticket, "--relay", "disabled", "--yes"])
+        .run()
+        .unwrap();
+    send_cmd.kill().unwrap();
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(text.contains("already exists"), "{text}");
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(
+        std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+        b"mine"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn recv_confirm() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    std::fs::write(src_dir.path().join("somefile.bin"), vec![1u8; 1000]).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &["send", "somefile.bin", "--relay", "disabled", "--no-daemon"],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    // the download is declined on the terminal
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let pty = nix::pty::openpty(None, None).unwrap();
+    let mut terminal = std::fs::File::from(pty.master);
+    terminal.write_all(b"n\n").unwrap();
+    let output = sendme_cmd(
+        data_dir.path(),
+        &["receive", &ticket, "--relay", "disabled"],
+    )
+    .dir(tgt_dir.path())
+    .stdin_file(std::fs::File::from(pty.slave))
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Download 1 files, 1000 B? [y/N]"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("download cancelled"), "{stderr}");
+    assert_eq!(output.status.code(), Some(8));
+    assert!(!tgt_dir.path().join("somefile.bin").exists());
+    // without a terminal to ask on, it is downloaded right away
+    let output = sendme_cmd(
+        data_dir.path(),
+        &["receive", &ticket, "--relay", "disabled"],
+    )
+    .dir(tgt_dir.path())
+    .stdin_bytes("n\n")
+    .stderr_capture()
+    .run()
+    .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("[y/N]"), "{stderr}");
+    assert_eq!(
+        std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+        vec![1u8; 1000]
+    );
+    send_cmd.kill().unwrap();
+}
+
+/// procfs has no free space at all, so nothing fits.
+#[cfg(target_os = "linux")]
+#[test]
+fn recv_no_space() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    std::fs::write(src_dir.path().join("somefile.bin"), vec![1u8; 1000]).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &["send", "somefile.bin", "--relay", "disabled", "--no-daemon"],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    let output = sendme_cmd(
+        data_dir.path(),
+        &["receive", &ticket, "--relay", "disabled", "--yes"],
+    )
+    .dir("/proc")
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    send_cmd.kill().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("not enough free space in /proc"),
+        "{stderr}"
+    );
+    assert_eq!(output.status.code(), Some(10));
+}
+
+#[test]
+fn send_failure_json() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let output = sendme_cmd(
+        data_dir.path(),
+        &["send", "missing.bin", "--no-daemon", "--json-progress"],
+    )
+    .dir(src_dir.path())
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let failed = stderr
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .find(|event| event["event"] == "failed")
+        .unwrap();
+    assert_eq!(failed["step"], "import");
+    assert!(
+        failed["message"].as_str().unwrap().contains("missing.bin"),
+        "{stderr}"
+    );
+    assert!(!failed["causes"].as_array().unwrap().is_empty(), "{stderr}");
+}
+
+#[cfg(unix)]
+#[test]
+fn send_shutdown_waits_for_transfers() {
+    use nix::{
+        sys::signal::{self, Signal},
+        unistd::Pid,
+    };
+
+    let src_dir = tempfile::tempdir().unwrap();
+    let tgt_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let data = vec![7u8; 8_000_000];
+    std::fs::write(src_dir.path().join("somefile.bin"), &data).unwrap();
+    let mut send_cmd = sendme_cmd(
+        data_dir.path(),
+        &["send", "somefile.bin", "--relay", "disabled", "--no-daemon"],
+    )
+    .dir(src_dir.path())
+    .stderr_to_stdout()
+    .reader()
+    .unwrap();
+    let (_, ticket) = read_ticket(3, &mut send_cmd);
+    // the limit makes the download take a few seconds
+    let recv_cmd = sendme_cmd(
+        data_dir.path(),
+        &[
+            "receive",
+            &ticket,
+            "--relay",
+            "disabled",
+            "--limit-rate",
+            "2MB/s",
+        ],
+    )
+    .dir(tgt_dir.path())
+    .stderr_to_stdout()
+    .stdout_capture()
+    .unchecked()
+    .start()
+    .unwrap();
+    // stop the sender once the download is well under way
+    fn downloaded(path: &Path) -> u64 {
+        match std::fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(Result::ok)
+                .map(|entry| downloaded(&entry.path()))
+                .sum(),
+            Err(_) if path.extension().is_some_and(|ext| ext == "data") => {
+                std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+            }
+            Err(_) => 0,
+        }
+    }
+    let start = std::time::Instant::now();
+    while downloaded(tgt_dir.path()) < 1_000_000 {
+        assert!(start.elapsed() < std::time::Duration::from_secs(30));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    for pid in send_cmd.pids() {
+        signal::kill(Pid::from_raw(pid as i32), Signal::SIGINT).unwrap();
+    }
+    let output = recv_cmd.wait().unwrap();
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{text}");
+    assert_eq!(
+        std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+        data
+    );
+    let mut rest = String::new();
+    send_cmd.read_to_string(&mut rest).unwrap();
+    assert!(rest.contains("waiting for 1 transfers to finish"), "{rest}");
+    // the store is gone once the sender is done
+    let leftovers = std::fs::read_dir(src_dir.path()).unwrap().count();
+    assert_eq!(leftovers, 1);
+}
+
+#[test]
+fn gc_removes_stale_dirs() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    std::fs::write(src_dir.path().join("somefile.bin"), vec![1u8; 1000]).unwrap();
+    // left behind by a process that was killed
+    let stale = src_dir.path().join(".sendme-get-0123");
+    std::fs::create_dir(&stale).unwrap();
+    std::fs::write(stale.join("sendme.lock"), "12345\n").unwrap();
+    // and one in the runtime directory, which is the data directory in tests
+    let stale_runtime = data_dir.path().join(".sendme-send-4567");
+    std::fs::create_dir_all(stale_runtime.join("data")).unwrap();
+    std::fs::write(stale_runtime.join("data/blob"), vec![2u8; 1000]).unwrap();
+    std::fs::write(stale_runtime.join("sendme.lock"), "4567\n").unwrap();
+    let sendme = |args: &[&str]| {
+        sendme_cmd(data_dir.path(), args)
+            .dir(src_dir.path())
+            .stderr_to_stdout()
+    };
+    let mut send_cmd = sendme(&["send", "somefile.bin", "--relay", "disabled", "--no-daemon"])
+        .reader()
+        .unwrap();
+    let (output, _) = read_ticket(6, &mut send_cmd);
+    assert!(output.contains(".sendme-get-0123"), "{output}");
+    assert!(output.contains("left by process 12345"), "{output}");
+    assert!(
+        output.contains(&stale_runtime.display().to_string()),
+        "{output}"
+    );
+    let gc_output = sendme(&["gc", "--yes"]).read().unwrap();
+    send_cmd.kill().unwrap();
+    assert!(gc_output.contains("removed 2 directories"), "{gc_output}");
+    assert!(!stale.exists());
+    assert!(!stale_runtime.exists());
+    // the store of the running send was left alone
+    let in_use = std::fs::read_dir(src_dir.path())
+        .unwrap()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(".sendme-send-")
+        })
+        .count();
+    assert_eq!(in_use, 1);
+}
+
+#[test]
+fn send_store_outside_cwd() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let data = vec![3u8; 100_000];
+    for store in ["runtime", "memory"] {
+        // a fresh runtime directory, the store of the killed send would be reported as left behind
+        let data_dir = tempfile::tempdir().unwrap();
+        let src_dir = tempfile::tempdir().unwrap();
+        let tgt_dir = tempfile::tempdir().unwrap();
+        std::fs::write(src_dir.path().join("somefile.bin"), &data).unwrap();
+        let sendme = |args: &[&str]| sendme_cmd(data_dir.path(), args);
+        let mut send_cmd = sendme(&[
+            "send",
+            "somefile.bin",
+            "--relay",
+            "disabled",
+            "--no-daemon",
+            "--store",
+            store,
+        ])
+        .dir(src_dir.path())
+        .stderr_to_stdout()
+        .reader()
+        .unwrap();
+        let (_, ticket) = read_ticket(3, &mut send_cmd);
+        // nothing but the data in the directory it is sent from
+        assert_eq!(std::fs::read_dir(src_dir.path()).unwrap().count(), 1);
+        sendme(&["receive", &ticket, "--relay", "disabled"])
+            .dir(tgt_dir.path())
+            .stderr_null()
+            .run()
+            .unwrap();
+        send_cmd.kill().unwrap();
+        assert_eq!(
+            std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+            data
+        );
+    }
+    // too much data to copy into memory, the file is sparse so this is quick
+    let src_dir = tempfile::tempdir().unwrap();
+    std::fs::File::create(src_dir.path().join("big.bin"))
+        .unwrap()
+        .set_len(300 * 1024 * 1024)
+        .unwrap();
+    let output = sendme_cmd(
+        data_dir.path(),
+        &["send", "big.bin", "--no-daemon", "--store", "memory"],
+    )
+    .dir(src_dir.path())
+    .stderr_capture()
+    .unchecked()
+    .run()
+    .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{stderr}");
+    assert!(
+        stderr.contains("--store memory only takes up to"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn send_twice_from_same_dir() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let store_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let data = vec![5u8; 1000];
+    std::fs::write(src_dir.path().join("somefile.bin"), &data).unwrap();
+    let store_arg = store_dir.path().to_str().unwrap();
+    let mut senders = Vec::new();
+    for extra in [&[][..], &["--store-dir", store_arg][..]] {
+        let mut args = vec!["send", "somefile.bin", "--relay", "disabled", "--no-daemon"];
+        args.extend_from_slice(extra);
+        let mut send_cmd = sendme_cmd(data_dir.path(), &args)
+            .dir(src_dir.path())
+            .stderr_to_stdout()
+            .reader()
+            .unwrap();
+        let (_, ticket) = read_ticket(3, &mut send_cmd);
+        senders.push((send_cmd, ticket));
+    }
+    assert_eq!(std::fs::read_dir(store_dir.path()).unwrap().count(), 1);
+    for (send_cmd, ticket) in senders {
+        let tgt_dir = tempfile::tempdir().unwrap();
+        sendme_cmd(
+            data_dir.path(),
+            &["receive", &ticket, "--relay", "disabled"],
+        )
+        .dir(tgt_dir.path())
+        .stderr_null()
+        .run()
+        .unwrap();
+        send_cmd.kill().unwrap();
+        assert_eq!(
+            std::fs::read(tgt_dir.path().join("somefile.bin")).unwrap(),
+            data
+        );
+    }
+}
+
+#[test]
+fn send_with_hash_cache() {
+    let src_dir = tempfile::tempdir().unwrap();
+    let data_dir = tempfile::tempdir().unwrap();
+    let dir = src_dir.path().join("dir");
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("same.bin"), vec![6u8; 5_000_000]).unwrap();
+    // large enough to get outboard files in the store
+    std::fs::write(dir.join("changed.bin"), vec![7u8; 5_000_000]).unwrap();
+    let outboards = || {
+        std::fs::read_dir(data_dir.path().join("hash-cache/store/data"))
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("obao4".as_ref()))
+            .count()
+    };
+    let send = |lines: usize| {
+        let args = [
+            "send",
+            "dir",
+            "--relay",
+            "disabled",
+            "--no-daemon",
+            "--hash-cache",
+        ];
+        let mut send_cmd = sendme_cmd(data_dir.path(), &args)
+            .dir(src_dir.path())
+            .stderr_to_stdout()
+            .reader()
+            .unwrap();
+        let (output, ticket) = read_ticket(lines, &mut send_cmd);
+        (send_cmd, output, ticket)
+    };
+    let (send_cmd, output, _) = send(3);
+    send_cmd.kill().unwrap();
+    assert!(!output.contains("unchanged"), "{output}");
+    // nothing is left in the directory it is sent from
+    assert_eq!(std::fs::read_dir(src_dir.path()).unwrap().count(), 1);
+    assert!(data_dir
+        .path()
+        .join("hash-cache")
+        .join("files.json")
+        .exists());
+
+    assert_eq!(outboards(), 2);
+
+    std::fs::write(dir.join("changed.bin"), vec![8u8; 6_000_000]).unwrap();
+    let (send_cmd, output, ticket) = send(4);
+    assert!(output.contains("1 of 2 files are unchanged"), "{output}");
+    // the outboard of the old content of the changed file is gone
+    assert_eq!(outboards(), 2);
+    let tgt_dir = tempfile::tempdir().unwrap();
+    sendme_cmd(
+        data_dir.path(),
+        &["receive", &ticket, "--relay", "disabled"],
+    )
+    .dir(tgt_dir.path())
+    .stderr_null()
+    .run()
+    .unwrap();
+    send_cmd.kill().unwrap();
+    let received = tgt_dir.path().join("dir");
+    assert_eq!(
+        std::fs::read(received.join("same.bin")).unwrap(),
+        vec![6u8; 5_000_000]
+    );
+    assert_eq!(
+        std::fs::read(received.join("changed.bin")).unwrap(),
+        vec![8u8; 6_000_000]
+    );
+}